@@ -0,0 +1,353 @@
+use crate::config::{BundleSetting, Config};
+use crate::wslconfig::WslConfig;
+use crate::{dns, logging, wsl};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("IoError writing bundle: {0}")]
+    Io(
+        #[source]
+        #[from]
+        std::io::Error,
+    ),
+    #[error("Zip error writing bundle: {0}")]
+    Zip(
+        #[source]
+        #[from]
+        zip::result::ZipError,
+    ),
+}
+
+/// Default location to save a diagnostics bundle
+pub fn default_path() -> PathBuf {
+    let date = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    dirs::desktop_dir()
+        .unwrap_or_else(logging::directory)
+        .join(format!("wsl2-dns-agent-diagnostics-{date}.zip"))
+}
+
+/// Collects the logs, config, network state, and the state of each distribution into a zip file
+pub fn create(path: &Path, setting: &BundleSetting, limits: &wsl::Limits) -> Result<(), Error> {
+    let mut files = Vec::<(String, String)>::new();
+
+    for entry in fs::read_dir(logging::directory())?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.ends_with(".txt") || name.ends_with(".jsonl") {
+            files.push((format!("logs/{name}"), read(&entry.path())));
+        }
+    }
+    files.push(("config.toml".to_string(), read(&Config::path())));
+    files.push(("wsl/wslconfig.txt".to_string(), read(&WslConfig::path())));
+
+    let mut suffixes = Vec::new();
+    let adapters = match dns::get_adapters() {
+        Ok(adapters) => {
+            suffixes = adapters
+                .iter()
+                .flat_map(|a| a.dns_suffixes().to_vec())
+                .collect();
+            to_json(&adapters)
+        }
+        Err(e) => format!("Error: {e}"),
+    };
+    files.push(("network/adapters.json".to_string(), adapters));
+    let routes = dns::get_routes()
+        .map(|r| to_json(&r))
+        .unwrap_or_else(|e| format!("Error: {e}"));
+    files.push(("network/routes.json".to_string(), routes));
+
+    files.push((
+        "wsl/list.txt".to_string(),
+        wsl::list_verbose(limits).unwrap_or_else(|e| format!("Error: {e}")),
+    ));
+    match wsl::get_distributions(limits) {
+        Ok(distributions) => {
+            for d in distributions.iter().filter(|d| d.version == 2) {
+                let commands: [(&str, &[&str]); 4] = [
+                    ("resolv.conf", &["cat", "/etc/resolv.conf"]),
+                    ("wsl.conf", &["cat", "/etc/wsl.conf"]),
+                    ("lsattr.txt", &["lsattr", "/etc/resolv.conf"]),
+                    (
+                        "lookup.txt",
+                        &["getent", "hosts", setting.lookup_name.as_str()],
+                    ),
+                ];
+                for (file, command) in commands {
+                    let output = d
+                        .exec(limits, command)
+                        .unwrap_or_else(|e| format!("Error: {}", e.summary()));
+                    files.push((format!("wsl/{}/{}", d.name, file), output));
+                }
+            }
+        }
+        Err(e) => files.push(("wsl/error.txt".to_string(), e.to_string())),
+    }
+
+    // The suffixes may have changed since the logs were written
+    suffixes.extend(logged_suffixes(&files));
+    let mut redactor = Redactor::new(setting, suffixes);
+    let mut zip = ZipWriter::new(File::create(path)?);
+    for (name, contents) in files {
+        zip.start_file(name, FileOptions::default())?;
+        zip.write_all(redactor.redact(&contents).as_bytes())?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+fn read(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| format!("Error: {e}"))
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string_pretty(value).unwrap()
+}
+
+/// The DNS suffixes recorded in the event log, and in the search lines of any resolv.conf
+fn logged_suffixes(files: &[(String, String)]) -> Vec<String> {
+    let mut suffixes = Vec::new();
+    for (name, contents) in files {
+        if name.ends_with(".jsonl") {
+            for record in contents
+                .lines()
+                .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+            {
+                let adapters = record["adapters"].as_array().into_iter().flatten();
+                let lists = adapters
+                    .map(|adapter| &adapter["dns_suffixes"])
+                    .chain([&record["dns"]["suffixes"]]);
+                for list in lists.filter_map(serde_json::Value::as_array) {
+                    suffixes.extend(list.iter().filter_map(|s| s.as_str()).map(String::from));
+                }
+            }
+        }
+        for line in contents.lines() {
+            if let Some(search) = line.trim_start().strip_prefix("search ") {
+                suffixes.extend(search.split_whitespace().map(String::from));
+            }
+        }
+    }
+    suffixes
+}
+
+/// Whether the character can be part of a domain label
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Replaces the domain (ignoring case) wherever it appears as whole labels, so that
+/// "corp.example.com" is replaced in "host.corp.example.com" but not in "mycorp.example.com"
+fn replace_domain(contents: &str, domain: &str, placeholder: &str) -> String {
+    let lower = contents.to_ascii_lowercase();
+    let domain = domain.to_ascii_lowercase();
+    let mut output = String::with_capacity(contents.len());
+    let mut position = 0;
+    while let Some(found) = lower[position..].find(&domain) {
+        let start = position + found;
+        let end = start + domain.len();
+        let before = contents[..start].chars().next_back();
+        let after = contents[end..].chars().next();
+        output.push_str(&contents[position..start]);
+        if !before.is_some_and(is_label_char) && !after.is_some_and(is_label_char) {
+            output.push_str(placeholder);
+        } else {
+            output.push_str(&contents[start..end]);
+        }
+        position = end;
+    }
+    output.push_str(&contents[position..]);
+    output
+}
+
+/// Replaces potentially sensitive values with consistent placeholders
+struct Redactor {
+    addresses: bool,
+    domains: Vec<String>,
+    username: Option<String>,
+    replacements: HashMap<String, String>,
+}
+
+impl Redactor {
+    fn new(setting: &BundleSetting, mut domains: Vec<String>) -> Self {
+        // Longer domains first, so a suffix of another domain doesn't replace only part of it
+        domains.retain(|domain| !domain.trim_matches('.').is_empty());
+        domains.iter_mut().for_each(|domain| {
+            *domain = domain.trim_matches('.').to_ascii_lowercase();
+        });
+        domains.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        domains.dedup();
+        Self {
+            addresses: setting.redact_addresses,
+            domains: if setting.redact_domains {
+                domains
+            } else {
+                vec![]
+            },
+            username: std::env::var("USERNAME")
+                .ok()
+                .filter(|_| setting.redact_username),
+            replacements: HashMap::new(),
+        }
+    }
+
+    fn placeholder(&mut self, kind: &str, value: &str) -> String {
+        let next = self.replacements.len() + 1;
+        self.replacements
+            .entry(value.to_string())
+            .or_insert_with(|| format!("<{kind}-{next}>"))
+            .clone()
+    }
+
+    fn redact(&mut self, contents: &str) -> String {
+        let mut contents = contents.to_string();
+        if self.addresses {
+            contents = self.redact_addresses(&contents);
+        }
+        for domain in self.domains.clone() {
+            let placeholder = self.placeholder("domain", &domain);
+            contents = replace_domain(&contents, &domain, &placeholder);
+        }
+        if let Some(username) = &self.username {
+            contents = contents.replace(&format!("Users\\{username}"), "Users\\<user>");
+            contents = contents.replace(&format!("Users\\\\{username}"), "Users\\\\<user>");
+        }
+        contents
+    }
+
+    /// Replaces every token that parses as an IP address, other than unspecified and loopback,
+    /// including IPv4 addresses followed by a port (IPv6 addresses with a port are bracketed)
+    /// and addresses ending a sentence
+    fn redact_addresses(&mut self, contents: &str) -> String {
+        let is_address_char = |c: char| c.is_ascii_hexdigit() || c == '.' || c == ':';
+        let mut output = String::with_capacity(contents.len());
+        let mut rest = contents;
+        while let Some(start) = rest.find(is_address_char) {
+            output.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|c: char| !is_address_char(c))
+                .unwrap_or(rest.len());
+            let token = &rest[..end];
+            match find_address(token) {
+                Some((start, end, ip)) if !ip.is_unspecified() && !ip.is_loopback() => {
+                    output.push_str(&token[..start]);
+                    output.push_str(&self.placeholder("ip", &token[start..end]));
+                    output.push_str(&token[end..]);
+                }
+                _ => output.push_str(token),
+            }
+            rest = &rest[end..];
+        }
+        output.push_str(rest);
+        output
+    }
+}
+
+/// The position of the address within a token of address characters, ignoring a port and any
+/// punctuation around it
+fn find_address(token: &str) -> Option<(usize, usize, IpAddr)> {
+    let trimmed = token.trim_matches(|c| c == '.' || c == ':');
+    let offset = token.find(trimmed).unwrap_or_default();
+    for (offset, candidate) in [(0, token), (offset, trimmed)] {
+        if let Ok(ip) = candidate.parse::<IpAddr>() {
+            return Some((offset, offset + candidate.len(), ip));
+        }
+        if let Some((address, _)) = candidate.split_once(':') {
+            if let Ok(ip) = address.parse::<Ipv4Addr>() {
+                return Some((offset, offset + address.len(), IpAddr::V4(ip)));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor() -> Redactor {
+        let setting = BundleSetting {
+            redact_addresses: true,
+            ..Default::default()
+        };
+        Redactor::new(&setting, vec![])
+    }
+
+    #[test]
+    fn redacts_addresses() {
+        let mut redactor = redactor();
+        assert_eq!(
+            redactor.redact("nameserver 10.0.0.1\nnameserver fe80::1\nnameserver 10.0.0.1"),
+            "nameserver <ip-1>\nnameserver <ip-2>\nnameserver <ip-1>"
+        );
+    }
+
+    #[test]
+    fn redacts_addresses_with_ports() {
+        let mut redactor = redactor();
+        assert_eq!(
+            redactor.redact("server 10.0.0.1:53, server [fd00::1]:53"),
+            "server <ip-1>:53, server [<ip-2>]:53"
+        );
+    }
+
+    #[test]
+    fn redacts_addresses_followed_by_punctuation() {
+        let mut redactor = redactor();
+        assert_eq!(
+            redactor.redact("Using 10.0.0.1. Then 10.0.0.2, 10.0.0.1:53. (fd00::1)"),
+            "Using <ip-1>. Then <ip-2>, <ip-1>:53. (<ip-3>)"
+        );
+    }
+
+    #[test]
+    fn redacts_whole_domain_labels() {
+        let setting = BundleSetting {
+            redact_domains: true,
+            ..Default::default()
+        };
+        let mut redactor = Redactor::new(
+            &setting,
+            vec!["corp.example.com".to_string(), "Example.com".to_string()],
+        );
+        assert_eq!(
+            redactor.redact("search corp.example.com host.Corp.Example.com mycorp.example.com"),
+            "search <domain-1> host.<domain-1> mycorp.<domain-2>"
+        );
+    }
+
+    #[test]
+    fn finds_logged_suffixes() {
+        let files = vec![
+            (
+                "logs/events.jsonl".to_string(),
+                r#"{"adapters":[{"dns_suffixes":["vpn.example.com"]}],"dns":{"suffixes":["home"]}}"#
+                    .to_string(),
+            ),
+            (
+                "wsl/Ubuntu/resolv.conf".to_string(),
+                "nameserver 10.0.0.1\nsearch corp.example.com lan\n".to_string(),
+            ),
+        ];
+        assert_eq!(
+            logged_suffixes(&files),
+            ["vpn.example.com", "home", "corp.example.com", "lan"]
+        );
+    }
+
+    #[test]
+    fn keeps_loopback_and_other_tokens() {
+        let mut redactor = redactor();
+        let contents = "127.0.0.1:53 ::1 0.0.0.0/0 version 2 abc:def";
+        assert_eq!(redactor.redact(contents), contents);
+    }
+}
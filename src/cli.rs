@@ -0,0 +1,106 @@
+use crate::bundle;
+use crate::config::Config;
+use crate::diagnostics;
+use crate::diagnostics::Severity;
+use std::path::PathBuf;
+use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+
+const USAGE: &str = "\
+Usage: wsl2-dns-agent.exe [COMMAND]
+
+Runs the tray application when no command is given.
+
+Commands:
+  bundle [--redact] [PATH]  Create a diagnostics bundle zip file
+  doctor                    Check the routes and interface metrics for misconfiguration";
+
+/// Runs a command line action instead of the tray application, returning the exit code
+pub fn run(args: &[String]) -> i32 {
+    // The application uses the windows subsystem, so output must be sent to the parent console
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+    match args[0].as_str() {
+        "bundle" => create_bundle(&args[1..]),
+        "doctor" => doctor(),
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            0
+        }
+        other => {
+            eprintln!("Unknown command: {other}\n\n{USAGE}");
+            1
+        }
+    }
+}
+
+/// Exits with 1 if there are any warnings
+fn doctor() -> i32 {
+    let config = match Config::try_load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return 1;
+        }
+    };
+    for warning in config.warnings() {
+        println!("Warning: {warning}");
+    }
+    let findings = match diagnostics::check(&config) {
+        Ok(findings) => findings,
+        Err(e) => {
+            eprintln!("Failed to check the network configuration: {e}");
+            return 1;
+        }
+    };
+    if findings.is_empty() {
+        println!("No problems found");
+    }
+    for finding in &findings {
+        println!("{}", finding.describe());
+    }
+    if findings.iter().any(|f| f.severity == Severity::Warning) {
+        1
+    } else {
+        0
+    }
+}
+
+fn create_bundle(args: &[String]) -> i32 {
+    let config = match Config::try_load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return 1;
+        }
+    };
+    let limits = config.wsl_limits();
+    let mut setting = config.bundle;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--redact" => setting = setting.redact_all(),
+            other if other.starts_with('-') => {
+                eprintln!("Unknown option: {other}\n\n{USAGE}");
+                return 1;
+            }
+            other if path.is_none() => path = Some(PathBuf::from(other)),
+            other => {
+                eprintln!("Unexpected argument: {other}\n\n{USAGE}");
+                return 1;
+            }
+        }
+    }
+    let path = path.unwrap_or_else(bundle::default_path);
+    println!("Creating diagnostics bundle...");
+    match bundle::create(&path, &setting, &limits) {
+        Ok(_) => {
+            println!("Saved diagnostics bundle to: {}", path.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to create diagnostics bundle: {e}");
+            1
+        }
+    }
+}
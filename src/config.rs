@@ -1,8 +1,13 @@
+use crate::dns::AdapterType;
+use crate::wsl;
 use crate::APP_NAME;
+use log::LevelFilter;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
 
 const EXCLUDE_BY_DEFAULT: &[&str] = &[
     "docker-desktop",
@@ -16,12 +21,92 @@ pub struct Config {
     /// Show toast notifications when DNS update is applied
     #[serde(default = "r#true")]
     pub show_notifications: bool,
+    /// How much detail to include in the notification when DNS update is applied
+    #[serde(default)]
+    pub notification_verbosity: NotificationVerbosity,
+    /// Show a notification even if the DNS configuration hasn't changed since the last update
+    #[serde(default)]
+    pub notify_unchanged: bool,
+    /// Show toast notifications when DNS update fails
+    #[serde(default = "r#true")]
+    pub show_error_notifications: bool,
+    /// Minimum number of seconds between error notifications, to avoid repeated
+    /// notifications while a VPN connection is flapping
+    #[serde(default = "default_error_notification_interval")]
+    pub error_notification_interval: u64,
+    /// Maximum number of distributions to update at the same time
+    #[serde(default = "default_max_parallel_updates")]
+    pub max_parallel_updates: usize,
+    /// Give up waiting for a distribution to update after this many seconds
+    #[serde(default = "default_distribution_timeout_secs")]
+    pub distribution_timeout_secs: u64,
+    /// Kill each call to wsl.exe if it doesn't exit within this many seconds
+    #[serde(default = "default_wsl_timeout_secs")]
+    pub wsl_timeout_secs: u64,
+    /// Rules for which network adapters to take DNS servers from
+    #[serde(default)]
+    pub adapters: AdapterSetting,
+    /// Settings for enforcing the interface metrics of adapters
+    #[serde(default)]
+    pub metrics: MetricSetting,
+    /// Settings for detecting routes that conflict with the WSL subnet
+    #[serde(default)]
+    pub routing: RoutingSetting,
+    /// Settings for checking the network for misconfiguration
+    #[serde(default)]
+    pub diagnostics: DiagnosticSetting,
+    /// Settings for managing the global WSL2 settings in .wslconfig
+    #[serde(default)]
+    pub wslconfig: WslConfigSetting,
+    /// Settings for which network changes to listen for
+    #[serde(default)]
+    pub events: EventSetting,
+    /// Settings for waiting for further changes before running
+    #[serde(default)]
+    pub debounce: DebounceSetting,
+    /// Settings for retrying after transient failures
+    #[serde(default)]
+    pub retry: RetrySetting,
+    /// Settings for the application log
+    #[serde(default)]
+    pub logging: LogSetting,
+    /// Settings for probing which DNS servers are reachable from Windows
+    #[serde(default)]
+    pub probe: ProbeSetting,
+    /// Settings for checking DNS works within each distribution after it is updated
+    #[serde(default)]
+    pub health_check: HealthCheckSetting,
+    /// Settings for creating a diagnostics bundle
+    #[serde(default)]
+    pub bundle: BundleSetting,
+    /// Settings for detecting distributions that are added or started
+    #[serde(default)]
+    pub watcher: WatchSetting,
     /// Fallback settings if named distribution setting is not specified
     #[serde(default)]
     defaults: DistributionSetting,
     /// Per distribution settings
     #[serde(default = "default_distributions")]
     distributions: HashMap<String, DistributionSetting>,
+    /// Problems found when loading the config, to be logged once logging is setup
+    #[serde(skip)]
+    warnings: Vec<String>,
+}
+
+fn default_error_notification_interval() -> u64 {
+    60
+}
+
+fn default_max_parallel_updates() -> usize {
+    4
+}
+
+fn default_distribution_timeout_secs() -> u64 {
+    60
+}
+
+fn default_wsl_timeout_secs() -> u64 {
+    30
 }
 
 fn default_distributions() -> HashMap<String, DistributionSetting> {
@@ -39,7 +124,393 @@ fn default_distributions() -> HashMap<String, DistributionSetting> {
     map
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationVerbosity {
+    /// Only state that the DNS configuration was updated
+    Minimal,
+    /// List the DNS servers and search suffixes
+    Summary,
+    /// List the DNS servers, search suffixes, and the distributions that were updated
+    Detailed,
+}
+
+impl Default for NotificationVerbosity {
+    fn default() -> Self {
+        Self::Detailed
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AdapterSetting {
+    /// How to order the adapters, after any that match a prefer rule
+    #[serde(default)]
+    pub ranking: AdapterRanking,
+    /// Use adapters matching any of these rules, even without a route to the internet
+    #[serde(default)]
+    pub include: Vec<AdapterRule>,
+    /// Ignore adapters matching any of these rules
+    #[serde(default)]
+    pub exclude: Vec<AdapterRule>,
+    /// Rank adapters matching these rules first, in the order of the rules, regardless of metric
+    #[serde(default)]
+    pub prefer: Vec<AdapterRule>,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdapterRanking {
+    /// By interface metric, the same as Windows
+    Metric,
+    /// Connected VPN adapters first, then by interface metric
+    VpnFirst,
+    /// Only use adapters that match a prefer rule (or by metric, if there are no prefer rules)
+    ExplicitOrder,
+}
+
+impl Default for AdapterRanking {
+    fn default() -> Self {
+        Self::Metric
+    }
+}
+
+/// Matches adapters by every property that is specified
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AdapterRule {
+    /// Part of the adapter name, as shown in Network Connections (e.g. "Ethernet 2")
+    pub name: Option<String>,
+    /// Part of the adapter description (e.g. "Cisco AnyConnect")
+    pub description: Option<String>,
+    /// The adapter GUID
+    pub guid: Option<String>,
+    /// The kind of adapter
+    pub r#type: Option<AdapterType>,
+}
+
+impl AdapterRule {
+    /// A rule without any properties doesn't match anything
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.description.is_none()
+            && self.guid.is_none()
+            && self.r#type.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MetricSetting {
+    /// Set the interface metrics of connected adapters matching the rules whenever the network
+    /// changes (requires running as administrator)
+    #[serde(default)]
+    pub enabled: bool,
+    /// The first rule that matches an adapter applies to it
+    #[serde(default)]
+    pub rules: Vec<MetricRule>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MetricRule {
+    /// Which adapters the rule applies to
+    pub adapter: AdapterRule,
+    /// The IPv4 interface metric to set, if any
+    pub ipv4: Option<u32>,
+    /// The IPv6 interface metric to set, if any
+    pub ipv6: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RoutingSetting {
+    /// Check for routes that send traffic for the WSL subnet to another interface (e.g. a VPN)
+    /// whenever the network changes
+    #[serde(default)]
+    pub enabled: bool,
+    /// Raise the metric of conflicting routes above the WSL route (requires running as
+    /// administrator)
+    #[serde(default)]
+    pub fix: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DiagnosticSetting {
+    /// Check for misconfigured routes and interface metrics before each run, and log any
+    /// problems that are found
+    #[serde(default = "r#true")]
+    pub enabled: bool,
+    /// Show a notification when a new problem is found
+    #[serde(default)]
+    pub notify: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WslConfigSetting {
+    /// Update .wslconfig before each run so that it has the desired values
+    #[serde(default)]
+    pub reconcile: bool,
+    /// Values for .wslconfig, by section and then key
+    #[serde(default)]
+    pub desired: BTreeMap<String, BTreeMap<String, toml::Value>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EventSetting {
+    /// Run when a network interface changes, as well as when the routes change
+    #[serde(default = "r#true")]
+    pub interface_changes: bool,
+    /// Run when an IP address is added or removed
+    #[serde(default = "r#true")]
+    pub address_changes: bool,
+    /// Check for changes to the routes and adapters this often, in case any notifications are
+    /// missed (0 to disable)
+    #[serde(default = "default_events_poll_secs")]
+    pub poll_secs: u64,
+}
+
+fn default_events_poll_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DebounceSetting {
+    /// Whether to also wait for the network to settle after a route change
+    #[serde(default)]
+    pub mode: DebounceMode,
+    /// Time to wait for further requests after the first one
+    #[serde(default = "default_debounce_delay_ms")]
+    pub delay_ms: u64,
+    /// In settle mode, the routes and adapters must be unchanged for this long
+    #[serde(default = "default_debounce_stable_ms")]
+    pub stable_ms: u64,
+    /// In settle mode, run anyway once this long has passed since the first request
+    #[serde(default = "default_debounce_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+fn default_debounce_delay_ms() -> u64 {
+    300
+}
+
+fn default_debounce_stable_ms() -> u64 {
+    2000
+}
+
+fn default_debounce_max_delay_ms() -> u64 {
+    15000
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DebounceMode {
+    /// Only wait for the fixed delay
+    Fixed,
+    /// Wait until the routes and adapters have stopped changing
+    Settle,
+}
+
+impl Default for DebounceMode {
+    fn default() -> Self {
+        Self::Fixed
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetrySetting {
+    /// Number of times to retry a run that failed due to a transient error
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, which doubles for each subsequent retry
+    #[serde(default = "default_retry_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+    /// Maximum delay between retries
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Run again this many seconds after a route change, in case DNS servers are assigned late
+    /// (0 to disable)
+    #[serde(default = "default_retry_follow_up_secs")]
+    pub follow_up_secs: u64,
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_initial_delay_ms() -> u64 {
+    1000
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    30000
+}
+
+fn default_retry_follow_up_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogSetting {
+    /// Minimum level of messages to write to the log
+    #[serde(default = "default_log_level")]
+    pub level: LogLevel,
+    /// Start a new log file once the current one reaches this size
+    #[serde(default = "default_log_max_size_mb")]
+    pub max_size_mb: u64,
+    /// Number of previous log files to keep
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+    /// Delete previous log files older than this
+    #[serde(default = "default_log_max_age_days")]
+    pub max_age_days: u64,
+    /// Also write a structured record of every run to "events.jsonl"
+    #[serde(default)]
+    pub event_log: bool,
+    /// Override the log level for specific modules, e.g. `dns`, `wsl` or `runner`
+    #[serde(default)]
+    pub modules: HashMap<String, LogLevel>,
+}
+
+fn default_log_level() -> LogLevel {
+    LogLevel::Info
+}
+
+fn default_log_max_size_mb() -> u64 {
+    10
+}
+
+fn default_log_max_files() -> usize {
+    5
+}
+
+fn default_log_max_age_days() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProbeSetting {
+    /// Send a query to each DNS server before applying, reordering them by reachability
+    #[serde(default)]
+    pub enabled: bool,
+    /// Name to query the A record of
+    #[serde(default = "default_probe_name")]
+    pub name: String,
+    /// Maximum time to wait for a response over each of UDP and TCP
+    #[serde(default = "default_probe_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How long to remember the result of a probe
+    #[serde(default = "default_probe_cache_secs")]
+    pub cache_secs: u64,
+    /// Remove unreachable servers entirely, rather than moving them to the end
+    #[serde(default)]
+    pub drop_unreachable: bool,
+}
+
+fn default_probe_name() -> String {
+    "github.com".to_string()
+}
+
+fn default_probe_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_probe_cache_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HealthCheckSetting {
+    /// Lookup the test names, and connect to each DNS server, after updating a distribution
+    #[serde(default)]
+    pub enabled: bool,
+    /// Names to lookup from within each distribution
+    #[serde(default = "default_health_check_names")]
+    pub names: Vec<String>,
+    /// Maximum time to wait for each lookup or connection
+    #[serde(default = "default_health_check_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_health_check_names() -> Vec<String> {
+    vec!["github.com".to_string()]
+}
+
+fn default_health_check_timeout_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BundleSetting {
+    /// Replace IP addresses with placeholders
+    #[serde(default)]
+    pub redact_addresses: bool,
+    /// Replace DNS suffixes with placeholders
+    #[serde(default)]
+    pub redact_domains: bool,
+    /// Replace the Windows user name in paths with a placeholder
+    #[serde(default)]
+    pub redact_username: bool,
+    /// Name to lookup from within each distribution
+    #[serde(default = "default_lookup_name")]
+    pub lookup_name: String,
+}
+
+fn default_lookup_name() -> String {
+    "github.com".to_string()
+}
+
+impl BundleSetting {
+    /// Enables all redaction options
+    pub fn redact_all(mut self) -> Self {
+        self.redact_addresses = true;
+        self.redact_domains = true;
+        self.redact_username = true;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WatchSetting {
+    /// Poll the list of distributions, and apply DNS to any that are added or started
+    #[serde(default = "r#true")]
+    pub enabled: bool,
+    /// Interval between polls after a change is seen
+    #[serde(default = "default_watch_min_interval_secs")]
+    pub min_interval_secs: u64,
+    /// The interval doubles while nothing changes, up to this limit
+    #[serde(default = "default_watch_max_interval_secs")]
+    pub max_interval_secs: u64,
+}
+
+fn default_watch_min_interval_secs() -> u64 {
+    5
+}
+
+fn default_watch_max_interval_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DistributionSetting {
     /// Whether to update the wsl.conf and resolv.conf files for this distribution
     #[serde(default = "r#true")]
@@ -58,41 +529,119 @@ pub fn r#true() -> bool {
     true
 }
 
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unable to read config file: {}: {}", .0.display(), .1)]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Unable to parse config file: {}: {}", .0.display(), .1)]
+    Parse(PathBuf, #[source] toml::de::Error),
+}
+
 impl Config {
-    /// Attempts to read config from AppData otherwise falls back to defaults
-    pub fn load() -> Self {
+    /// Location of the config file in "AppData\Roaming\WSL2 DNS Agent\config.toml"
+    pub fn path() -> PathBuf {
         let roaming_appdata = dirs::config_dir().unwrap().join(APP_NAME);
         fs::create_dir_all(&roaming_appdata).unwrap();
-        let config_path = roaming_appdata.join("config.toml");
+        roaming_appdata.join("config.toml")
+    }
+
+    /// Reads the config file from AppData, creating a default one if it doesn't exist. This is
+    /// called before logging is setup, so anything worth logging is returned in `warnings()`
+    pub fn try_load() -> Result<Self, Error> {
+        let config_path = Self::path();
         if !config_path.exists() {
-            log::warn!("Config file doesn't exist, creating default");
-            let new = Self::default();
-            new.save(&config_path);
-            return new;
+            let mut new = Self::default();
+            new.warnings
+                .push("Config file doesn't exist, creating default".to_string());
+            if let Err(e) = new.save(&config_path) {
+                new.warnings
+                    .push(format!("Failed to write config file: {e}"));
+            }
+            return Ok(new);
+        }
+        let contents =
+            fs::read_to_string(&config_path).map_err(|e| Error::Read(config_path.clone(), e))?;
+        let mut config: Self =
+            toml::from_str(&contents).map_err(|e| Error::Parse(config_path, e))?;
+        // Unknown keys are ignored rather than rejected, so that a config that loaded with an
+        // older version (or has a typo) still loads
+        if let (Ok(file), Ok(known)) = (
+            toml::from_str::<toml::Value>(&contents),
+            toml::Value::try_from(&config),
+        ) {
+            let mut unknown = vec![];
+            unknown_keys(&file, &known, "", &mut unknown);
+            config.warnings.extend(
+                unknown
+                    .into_iter()
+                    .map(|key| format!("Ignoring unknown config option: {key}")),
+            );
         }
-        let contents = match fs::read_to_string(&config_path) {
-            Err(e) => panic!(
-                "Unable to read config file: {}: {}",
-                config_path.display(),
-                e
-            ),
-            Ok(s) => s,
-        };
-        match toml::from_str(&contents) {
-            Err(e) => panic!(
-                "Unable to parse config file: {}: {}",
-                config_path.display(),
-                e
-            ),
-            Ok(config) => config,
+        // Otherwise every adapter would be ignored, leaving no DNS servers
+        if config.adapters.ranking == AdapterRanking::ExplicitOrder
+            && config.adapters.prefer.is_empty()
+        {
+            config.warnings.push(
+                "The explicit_order adapter ranking has no prefer rules, ranking by metric instead"
+                    .to_string(),
+            );
+            config.adapters.ranking = AdapterRanking::Metric;
         }
+        Ok(config)
     }
 
-    fn save(&self, path: &PathBuf) {
+    /// Sections that differ from `new` but are only read at startup, so changes to them don't
+    /// take effect until the application is restarted
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        fn differs<T: Serialize>(old: &T, new: &T) -> bool {
+            toml::Value::try_from(old).ok() != toml::Value::try_from(new).ok()
+        }
+        let mut sections = vec![];
+        if differs(&self.logging, &new.logging) {
+            sections.push("logging");
+        }
+        if differs(&self.events, &new.events) {
+            sections.push("events");
+        }
+        if differs(&self.watcher, &new.watcher) {
+            sections.push("watcher");
+        }
+        sections
+    }
+
+    /// Problems found when loading the config, which didn't prevent it from loading
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    fn save(&self, path: &PathBuf) -> std::io::Result<()> {
         let contents = toml::to_string(&self).unwrap();
-        if let Err(err) = fs::write(path, contents) {
-            log::error!("Failed to write config file: {err}");
+        fs::write(path, contents)
+    }
+}
+
+/// The keys in the file that aren't present in the parsed config
+fn unknown_keys(file: &toml::Value, known: &toml::Value, path: &str, unknown: &mut Vec<String>) {
+    match (file, known) {
+        (toml::Value::Table(file), toml::Value::Table(known)) => {
+            for (key, value) in file {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                match known.get(key) {
+                    Some(known) => unknown_keys(value, known, &path, unknown),
+                    None => unknown.push(path),
+                }
+            }
         }
+        (toml::Value::Array(file), toml::Value::Array(known)) => {
+            for (index, (value, known)) in file.iter().zip(known).enumerate() {
+                unknown_keys(value, known, &format!("{path}[{index}]"), unknown);
+            }
+        }
+        _ => {}
     }
 }
 
@@ -102,6 +651,84 @@ impl Default for Config {
     }
 }
 
+impl Default for AdapterSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for MetricSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for RoutingSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for DiagnosticSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for WslConfigSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for EventSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for DebounceSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for RetrySetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for LogSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for ProbeSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for HealthCheckSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for BundleSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Default for WatchSetting {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
 impl Default for DistributionSetting {
     fn default() -> Self {
         toml::from_str("").unwrap()
@@ -114,4 +741,36 @@ impl Config {
             .get(distribution)
             .unwrap_or(&self.defaults)
     }
+
+    /// Whether any distributions are shut down once they have been updated
+    pub fn shuts_down_distributions(&self) -> bool {
+        self.defaults.shutdown || self.distributions.values().any(|d| d.shutdown)
+    }
+
+    /// The limits on each call to wsl.exe
+    pub fn wsl_limits(&self) -> wsl::Limits {
+        wsl::Limits::new(Duration::from_secs(self.wsl_timeout_secs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_sections_that_require_a_restart() {
+        let old = Config::default();
+        let new: Config = toml::from_str(
+            r#"
+            show_notifications = false
+            [logging]
+            level = "debug"
+            [watcher]
+            enabled = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(old.restart_required(&new), vec!["logging", "watcher"]);
+        assert!(old.restart_required(&Config::default()).is_empty());
+    }
 }
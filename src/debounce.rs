@@ -0,0 +1,233 @@
+use crate::config::{DebounceMode, DebounceSetting};
+use crate::request::{Batch, RunRequest};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+/// How often to check whether the network has settled
+const SETTLE_POLL: Duration = Duration::from_millis(250);
+
+/// Where requests are received from while debouncing, along with the time they are measured by
+pub trait Requests {
+    fn now(&self) -> Instant;
+
+    /// Waits for the next request, giving up at the deadline
+    fn recv_until(&mut self, deadline: Instant) -> Option<RunRequest>;
+}
+
+impl Requests for Receiver<RunRequest> {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn recv_until(&mut self, deadline: Instant) -> Option<RunRequest> {
+        self.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .ok()
+    }
+}
+
+/// Merges any requests received before the deadline, returning whether the network changed
+fn receive_until(rx: &mut impl Requests, batch: &mut Batch, deadline: Instant) -> bool {
+    let mut network_change = false;
+    while let Some(next) = rx.recv_until(deadline) {
+        network_change |= next.reason.is_network_change();
+        batch.merge(next);
+    }
+    network_change
+}
+
+/// Waits for further requests after the first, merging them into a single batch
+///
+/// In settle mode, `capture` is used to take snapshots of the network, to tell when it has
+/// stopped changing
+pub fn debounce<S: PartialEq>(
+    rx: &mut impl Requests,
+    first: RunRequest,
+    setting: &DebounceSetting,
+    capture: impl FnMut() -> Option<S>,
+) -> Batch {
+    let start = rx.now();
+    let mut batch = Batch::new(first);
+    receive_until(
+        rx,
+        &mut batch,
+        start + Duration::from_millis(setting.delay_ms),
+    );
+    if setting.mode == DebounceMode::Settle && batch.network_change {
+        settle(rx, &mut batch, setting, start, capture);
+    }
+    batch
+}
+
+/// Continues waiting until the snapshots have stopped changing, or the maximum delay
+fn settle<S: PartialEq>(
+    rx: &mut impl Requests,
+    batch: &mut Batch,
+    setting: &DebounceSetting,
+    start: Instant,
+    mut capture: impl FnMut() -> Option<S>,
+) {
+    let stable = Duration::from_millis(setting.stable_ms);
+    let max_deadline = start + Duration::from_millis(setting.max_delay_ms);
+    let mut last = capture();
+    let mut stable_since = rx.now();
+    while rx.now() - stable_since < stable {
+        let now = rx.now();
+        if now >= max_deadline {
+            log::warn!("Network didn't settle within {:?}", now - start);
+            return;
+        }
+        let network_change = receive_until(rx, batch, (now + SETTLE_POLL).min(max_deadline));
+        let current = capture();
+        if network_change || current != last {
+            stable_since = rx.now();
+            last = current;
+        }
+    }
+    log::info!("Network settled after {:?}", rx.now() - start);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RunReason;
+    use std::collections::VecDeque;
+
+    /// Requests that arrive at set times on a clock that only moves forward while waiting
+    struct Timeline {
+        start: Instant,
+        elapsed: Duration,
+        requests: VecDeque<(u64, RunReason)>,
+    }
+
+    impl Timeline {
+        fn new(requests: Vec<(u64, RunReason)>) -> Self {
+            Self {
+                start: Instant::now(),
+                elapsed: Duration::ZERO,
+                requests: requests.into(),
+            }
+        }
+
+        fn elapsed_ms(&self) -> u128 {
+            self.elapsed.as_millis()
+        }
+    }
+
+    impl Requests for Timeline {
+        fn now(&self) -> Instant {
+            self.start + self.elapsed
+        }
+
+        fn recv_until(&mut self, deadline: Instant) -> Option<RunRequest> {
+            let deadline = deadline - self.start;
+            match self.requests.front() {
+                Some((at_ms, _)) if Duration::from_millis(*at_ms) <= deadline => {
+                    let (at_ms, reason) = self.requests.pop_front().unwrap();
+                    self.elapsed = self.elapsed.max(Duration::from_millis(at_ms));
+                    Some(reason.into())
+                }
+                _ => {
+                    self.elapsed = self.elapsed.max(deadline);
+                    None
+                }
+            }
+        }
+    }
+
+    fn setting(mode: DebounceMode) -> DebounceSetting {
+        DebounceSetting {
+            mode,
+            delay_ms: 100,
+            stable_ms: 300,
+            max_delay_ms: 2000,
+        }
+    }
+
+    #[test]
+    fn merges_requests_within_the_delay() {
+        let mut timeline = Timeline::new(vec![
+            (10, RunReason::InterfaceChange),
+            (100, RunReason::AddressChange),
+            (101, RunReason::TrayButton),
+        ]);
+        let batch = debounce(
+            &mut timeline,
+            RunReason::RouteChange.into(),
+            &setting(DebounceMode::Fixed),
+            || None::<u32>,
+        );
+        assert!(matches!(batch.reason, RunReason::RouteChange));
+        assert_eq!(batch.debounced, 2);
+        assert!(batch.network_only);
+        assert_eq!(timeline.elapsed_ms(), 100);
+        // The request after the delay is left for the next run
+        assert_eq!(timeline.requests.len(), 1);
+    }
+
+    #[test]
+    fn only_network_changes_settle() {
+        let mut timeline = Timeline::new(vec![(
+            10,
+            RunReason::DistributionStarted("Debian".to_string()),
+        )]);
+        let batch = debounce(
+            &mut timeline,
+            RunReason::DistributionAdded("Ubuntu".to_string()).into(),
+            &setting(DebounceMode::Settle),
+            || -> Option<u32> { panic!("only network changes need to settle") },
+        );
+        assert_eq!(batch.debounced, 1);
+        assert!(batch.lifecycle_only);
+        assert_eq!(timeline.elapsed_ms(), 100);
+    }
+
+    #[test]
+    fn settles_once_the_network_stops_changing() {
+        let mut timeline = Timeline::new(vec![(400, RunReason::RouteChange)]);
+        let mut captures = 0;
+        let batch = debounce(
+            &mut timeline,
+            RunReason::RouteChange.into(),
+            &setting(DebounceMode::Settle),
+            || {
+                captures += 1;
+                Some(captures.min(3))
+            },
+        );
+        // The second route change is received while settling
+        assert_eq!(batch.debounced, 1);
+        // Captures at 100, then after each poll at 350, 600 (which received the route change),
+        // 850 and 1100. The snapshot stops changing at the third capture, so it is stable from
+        // 600 until 900, which the poll ending at 1100 sees
+        assert_eq!(captures, 5);
+        assert_eq!(timeline.elapsed_ms(), 1100);
+    }
+
+    #[test]
+    fn stops_settling_after_the_maximum_delay() {
+        let mut timeline = Timeline::new(vec![]);
+        let mut captures = 0;
+        debounce(
+            &mut timeline,
+            RunReason::AddressChange.into(),
+            &setting(DebounceMode::Settle),
+            || {
+                captures += 1;
+                Some(captures)
+            },
+        );
+        assert_eq!(timeline.elapsed_ms(), 2000);
+    }
+
+    #[test]
+    fn fixed_mode_does_not_settle() {
+        let mut timeline = Timeline::new(vec![]);
+        debounce(
+            &mut timeline,
+            RunReason::RouteChange.into(),
+            &setting(DebounceMode::Fixed),
+            || -> Option<u32> { panic!("fixed mode doesn't take snapshots") },
+        );
+        assert_eq!(timeline.elapsed_ms(), 100);
+    }
+}
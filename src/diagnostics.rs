@@ -0,0 +1,406 @@
+use crate::config::{AdapterRanking, Config};
+use crate::dns;
+use crate::dns::{Adapter, Route};
+use crate::metrics;
+use crate::routing;
+use crate::wslconfig;
+use crate::wslconfig::{WslConfig, WslConfigFile};
+use serde::Serialize;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Worth knowing, but the agent works around it
+    Info,
+    /// Likely to break DNS or networking, within WSL or Windows
+    Warning,
+}
+
+/// The misconfigurations that are checked for, mostly steps from docs/ROUTING.md
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// A route sends traffic for the WSL subnet elsewhere (step #1)
+    WslRouteConflict,
+    /// A VPN adapter has a lower metric than the WSL adapter (step #1)
+    VpnMetricBelowWsl,
+    /// Another adapter's DNS servers rank above the VPN's (step #3)
+    DnsOutranksVpn,
+    /// An option is enabled that requires running as administrator
+    NotElevated,
+    /// WSL forwards DNS queries to Windows itself, so the agent stands down
+    DnsTunneling,
+    /// dnsProxy is enabled, but the agent replaces the resolv.conf that would use it
+    DnsProxyUnused,
+    /// Distributions are shut down after being updated, which vmIdleTimeout already does
+    RedundantShutdown,
+    /// A value in .wslconfig differs from the value in [wslconfig] desired
+    WslConfigDrift,
+}
+
+/// A problem found with the network configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub rule: Rule,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, rule: Rule, message: String) -> Self {
+        Self {
+            severity,
+            rule,
+            message,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let severity = match self.severity {
+            Severity::Info => "Info",
+            Severity::Warning => "Warning",
+        };
+        format!("{severity}: {}", self.message)
+    }
+}
+
+/// Evaluates each rule against the routes, adapters, and .wslconfig
+pub fn evaluate(
+    routes: &[Route],
+    adapters: &[Adapter],
+    wsl_config: &WslConfigFile,
+    config: &Config,
+    elevated: bool,
+) -> Vec<Finding> {
+    let mut findings = vec![];
+    let conflicts = routing::find_conflicts(routes, &routing::interfaces(adapters));
+    for conflict in &conflicts {
+        findings.push(Finding::new(
+            Severity::Warning,
+            Rule::WslRouteConflict,
+            format!(
+                "WSL may not be able to reach the network, {} (see step #1 of docs/ROUTING.md)",
+                conflict.describe()
+            ),
+        ));
+    }
+
+    let vpns = adapters
+        .iter()
+        .filter(|a| a.is_vpn() && !routing::is_wsl_adapter(a))
+        .collect::<Vec<_>>();
+    if let Some(wsl) = adapters.iter().find(|a| routing::is_wsl_adapter(a)) {
+        for vpn in &vpns {
+            let conflicting = conflicts
+                .iter()
+                .any(|c| Some(c.route.interface_index) == vpn.ipv4_interface().map(|(i, _)| i));
+            if !conflicting && vpn.interface_metric() < wsl.interface_metric() {
+                findings.push(Finding::new(
+                    Severity::Warning,
+                    Rule::VpnMetricBelowWsl,
+                    format!(
+                        "{} has a lower interface metric ({}) than {} ({}), so its routes may \
+                         take precedence over WSL's (see step #1 of docs/ROUTING.md)",
+                        vpn.friendly_name(),
+                        vpn.interface_metric(),
+                        wsl.friendly_name(),
+                        wsl.interface_metric()
+                    ),
+                ));
+            }
+        }
+    }
+
+    // The order Windows uses, which may differ from the agent's ranking
+    let internet = adapters
+        .iter()
+        .filter(|a| a.has_internet_route(routes) && !a.dns_servers().is_empty())
+        .collect::<Vec<_>>();
+    let handled = config.adapters.ranking == AdapterRanking::VpnFirst;
+    for vpn in vpns.iter().filter(|v| internet.contains(v)) {
+        let handled = handled || config.adapters.prefer.iter().any(|r| vpn.matches(r));
+        for other in internet.iter().filter(|a| !a.is_vpn()) {
+            if other.interface_metric() >= vpn.interface_metric() {
+                continue;
+            }
+            let (severity, consequence) = if handled {
+                (Severity::Info, "Windows, but not WSL,")
+            } else {
+                (Severity::Warning, "Windows and WSL")
+            };
+            findings.push(Finding::new(
+                severity,
+                Rule::DnsOutranksVpn,
+                format!(
+                    "{} has a lower interface metric ({}) than {} ({}), so {consequence} will \
+                     use its DNS servers first (see step #3 of docs/ROUTING.md)",
+                    other.friendly_name(),
+                    other.interface_metric(),
+                    vpn.friendly_name(),
+                    vpn.interface_metric()
+                ),
+            ));
+        }
+    }
+
+    if !elevated {
+        let options = [
+            (config.metrics.enabled, "[metrics] enabled"),
+            (config.routing.fix, "[routing] fix"),
+        ];
+        for (_, option) in options.iter().filter(|(enabled, _)| *enabled) {
+            findings.push(Finding::new(
+                Severity::Warning,
+                Rule::NotElevated,
+                format!("{option} requires running the agent as administrator"),
+            ));
+        }
+    }
+
+    let settings = WslConfig::from_file(wsl_config);
+    if settings.dns_tunneling() {
+        findings.push(Finding::new(
+            Severity::Info,
+            Rule::DnsTunneling,
+            "dnsTunneling is enabled in .wslconfig, so WSL forwards DNS queries to Windows and \
+             the agent leaves resolv.conf alone"
+                .to_string(),
+        ));
+    } else if settings.dns_proxy == Some(true) {
+        findings.push(Finding::new(
+            Severity::Info,
+            Rule::DnsProxyUnused,
+            "dnsProxy is enabled in .wslconfig, but has no effect since the agent writes \
+             resolv.conf itself"
+                .to_string(),
+        ));
+    }
+    if let Some(timeout) = settings.vm_idle_timeout.filter(|t| *t >= 0) {
+        if config.shuts_down_distributions() {
+            findings.push(Finding::new(
+                Severity::Info,
+                Rule::RedundantShutdown,
+                format!(
+                    "shutdown is enabled, but WSL already shuts down the VM once it has been idle \
+                     for {timeout}ms (vmIdleTimeout in .wslconfig)"
+                ),
+            ));
+        }
+    }
+    // When reconciling, the values will be updated on the next run
+    if !config.wslconfig.reconcile {
+        for (section, key, desired) in wslconfig::desired_values(&config.wslconfig) {
+            if wsl_config.has_value(section, key, &desired) {
+                continue;
+            }
+            let current = wsl_config.get(section, key);
+            findings.push(Finding::new(
+                Severity::Info,
+                Rule::WslConfigDrift,
+                format!(
+                    "{section}.{key} is {} in .wslconfig rather than {desired}, enable \
+                     [wslconfig] reconcile to update it",
+                    current.unwrap_or("not set")
+                ),
+            ));
+        }
+    }
+    findings
+}
+
+/// Checks the current network configuration
+pub fn check(config: &Config) -> Result<Vec<Finding>, dns::Error> {
+    let wsl_config = WslConfigFile::load().unwrap_or_else(|e| {
+        log::warn!("{e}, ignoring .wslconfig");
+        WslConfigFile::default()
+    });
+    Ok(evaluate(
+        &dns::get_routes()?,
+        &dns::get_adapters()?,
+        &wsl_config,
+        config,
+        metrics::is_elevated(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::test_index::{ETHERNET, VPN, WSL};
+
+    fn wsl() -> Adapter {
+        Adapter::test(
+            "vEthernet (WSL)",
+            "Hyper-V Virtual Ethernet Adapter",
+            WSL,
+            15,
+            &[],
+        )
+    }
+
+    fn vpn(metric: u32) -> Adapter {
+        Adapter::test("VPN", "Cisco AnyConnect", VPN, metric, &["10.0.0.53"])
+    }
+
+    fn ethernet(metric: u32) -> Adapter {
+        Adapter::test(
+            "Ethernet",
+            "Intel Ethernet",
+            ETHERNET,
+            metric,
+            &["192.168.1.1"],
+        )
+    }
+
+    /// The WSL subnet, and a route to the internet via Ethernet and the VPN
+    fn routes() -> Vec<Route> {
+        vec![
+            Route::test(WSL, "172.20.0.0/20", "0.0.0.0", 256),
+            Route::test(ETHERNET, "0.0.0.0/0", "192.168.1.1", 0),
+            Route::test(VPN, "0.0.0.0/0", "10.0.0.1", 0),
+        ]
+    }
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn rules(findings: &[Finding]) -> Vec<(Severity, Rule)> {
+        findings.iter().map(|f| (f.severity, f.rule)).collect()
+    }
+
+    fn evaluate_routes(routes: &[Route], adapters: &[Adapter]) -> Vec<(Severity, Rule)> {
+        let findings = evaluate(
+            routes,
+            adapters,
+            &WslConfigFile::default(),
+            &Config::default(),
+            true,
+        );
+        rules(&findings)
+    }
+
+    #[test]
+    fn finds_nothing_wrong_with_a_healthy_network() {
+        assert!(evaluate_routes(&routes(), &[wsl(), vpn(20), ethernet(25)]).is_empty());
+    }
+
+    #[test]
+    fn reports_route_conflicts_instead_of_the_vpn_metric() {
+        let mut routes = routes();
+        routes.push(Route::test(VPN, "172.20.0.0/20", "10.0.0.1", 1));
+        assert_eq!(
+            evaluate_routes(&routes, &[wsl(), vpn(1), ethernet(25)]),
+            [(Severity::Warning, Rule::WslRouteConflict)]
+        );
+    }
+
+    #[test]
+    fn reports_a_vpn_metric_below_wsl() {
+        assert_eq!(
+            evaluate_routes(&routes(), &[wsl(), vpn(5), ethernet(25)]),
+            [(Severity::Warning, Rule::VpnMetricBelowWsl)]
+        );
+    }
+
+    #[test]
+    fn reports_dns_servers_ranked_above_the_vpn() {
+        assert_eq!(
+            evaluate_routes(&routes(), &[wsl(), vpn(30), ethernet(25)]),
+            [(Severity::Warning, Rule::DnsOutranksVpn)]
+        );
+        // The agent ranks the VPN first for WSL, but Windows still doesn't
+        let config = config("[adapters]\nranking = \"vpn_first\"");
+        let findings = evaluate(
+            &routes(),
+            &[wsl(), vpn(30), ethernet(25)],
+            &WslConfigFile::default(),
+            &config,
+            true,
+        );
+        assert_eq!(rules(&findings), [(Severity::Info, Rule::DnsOutranksVpn)]);
+    }
+
+    #[test]
+    fn ignores_adapters_without_an_internet_route_for_dns() {
+        let routes = routes()
+            .into_iter()
+            .filter(|r| r.interface_index != ETHERNET)
+            .collect::<Vec<_>>();
+        assert!(evaluate_routes(&routes, &[wsl(), vpn(30), ethernet(25)]).is_empty());
+    }
+
+    #[test]
+    fn reports_options_that_require_administrator() {
+        let config = config("[metrics]\nenabled = true\n[routing]\nenabled = true\nfix = true");
+        let evaluate = |elevated| {
+            let wsl_config = WslConfigFile::default();
+            rules(&evaluate(&[], &[], &wsl_config, &config, elevated))
+        };
+        assert_eq!(
+            evaluate(false),
+            [
+                (Severity::Warning, Rule::NotElevated),
+                (Severity::Warning, Rule::NotElevated)
+            ]
+        );
+        assert!(evaluate(true).is_empty());
+    }
+
+    #[test]
+    fn reports_dns_tunneling() {
+        let wsl_config = WslConfigFile::parse("[wsl2]\ndnsTunneling=true\n");
+        let findings = evaluate(&[], &[], &wsl_config, &Config::default(), true);
+        assert_eq!(rules(&findings), [(Severity::Info, Rule::DnsTunneling)]);
+    }
+
+    #[test]
+    fn reports_an_unused_dns_proxy() {
+        let wsl_config = WslConfigFile::parse("[wsl2]\ndnsProxy=true\n");
+        let findings = evaluate(&[], &[], &wsl_config, &Config::default(), true);
+        assert_eq!(rules(&findings), [(Severity::Info, Rule::DnsProxyUnused)]);
+        // Only dnsTunneling is reported, since WSL doesn't use the proxy either
+        let wsl_config = WslConfigFile::parse("[wsl2]\ndnsProxy=true\ndnsTunneling=true\n");
+        let findings = evaluate(&[], &[], &wsl_config, &Config::default(), true);
+        assert_eq!(rules(&findings), [(Severity::Info, Rule::DnsTunneling)]);
+    }
+
+    #[test]
+    fn reports_shutdown_with_a_vm_idle_timeout() {
+        let shutdown = config("[distributions.Ubuntu]\nshutdown = true");
+        let evaluate = |wsl_config: &str, config: &Config| {
+            let wsl_config = WslConfigFile::parse(wsl_config);
+            rules(&evaluate(&[], &[], &wsl_config, config, true))
+        };
+        assert_eq!(
+            evaluate("[wsl2]\nvmIdleTimeout=60000\n", &shutdown),
+            [(Severity::Info, Rule::RedundantShutdown)]
+        );
+        assert!(evaluate("[wsl2]\nvmIdleTimeout=-1\n", &shutdown).is_empty());
+        assert!(evaluate("", &shutdown).is_empty());
+        assert!(evaluate("[wsl2]\nvmIdleTimeout=60000\n", &Config::default()).is_empty());
+    }
+
+    #[test]
+    fn compares_wslconfig_booleans_ignoring_case() {
+        let wsl_config = WslConfigFile::parse("[wsl2]\ndnsProxy=False\n");
+        let config = config("[wslconfig.desired.wsl2]\ndnsProxy = false");
+        assert!(evaluate(&[], &[], &wsl_config, &config, true).is_empty());
+    }
+
+    #[test]
+    fn reports_wslconfig_drift_unless_reconciling() {
+        let wsl_config = WslConfigFile::parse("[wsl2]\nnetworkingMode=mirrored\n");
+        let desired = "[wslconfig.desired.wsl2]\nnetworkingMode = \"nat\"\ndnsProxy = false";
+        let findings = evaluate(&[], &[], &wsl_config, &config(desired), true);
+        assert_eq!(
+            rules(&findings),
+            [
+                (Severity::Info, Rule::WslConfigDrift),
+                (Severity::Info, Rule::WslConfigDrift)
+            ]
+        );
+        let reconcile = format!("[wslconfig]\nreconcile = true\n{desired}");
+        assert!(evaluate(&[], &[], &wsl_config, &config(&reconcile), true).is_empty());
+    }
+}
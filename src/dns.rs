@@ -1,24 +1,32 @@
+use crate::config::{AdapterRanking, AdapterRule, AdapterSetting};
+use crate::wslconfig::NetworkingMode;
 use crate::APP_NAME;
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
 use std::mem::transmute;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv6Addr};
 use std::ptr::{null_mut, slice_from_raw_parts};
 use thiserror::Error;
 use win32_utils::net::ToStdSocket;
 use win32_utils::str::FromWin32Str;
 use windows::Win32::Foundation::{ERROR_BUFFER_OVERFLOW, WIN32_ERROR};
 use windows::Win32::NetworkManagement::IpHelper::{
-    FreeMibTable, GetAdaptersAddresses, GetIpForwardTable2, GET_ADAPTERS_ADDRESSES_FLAGS,
-    IP_ADAPTER_ADDRESSES_LH, IP_ADAPTER_IPV4_ENABLED, IP_ADAPTER_IPV6_ENABLED, MIB_IPFORWARD_ROW2,
-    MIB_IPFORWARD_TABLE2,
+    FreeMibTable, GetAdaptersAddresses, GetIpForwardTable2, SetIpForwardEntry2,
+    GET_ADAPTERS_ADDRESSES_FLAGS, IP_ADAPTER_ADDRESSES_LH, IP_ADAPTER_IPV4_ENABLED,
+    IP_ADAPTER_IPV6_ENABLED, MIB_IPFORWARD_ROW2, MIB_IPFORWARD_TABLE2,
 };
 use windows::Win32::Networking::WinSock::AF_UNSPEC;
 
-#[derive(Debug)]
-struct Route {
-    interface_index: u32,
-    destination_prefix_ip: IpAddr,
-    destination_prefix_len: u8,
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Route {
+    pub interface_index: u32,
+    pub destination_prefix_ip: IpAddr,
+    pub destination_prefix_len: u8,
+    /// Unspecified for on-link routes
+    pub next_hop: IpAddr,
+    /// The route metric, the cost of the route also includes the interface metric
+    pub metric: u32,
 }
 
 impl Route {
@@ -26,34 +34,102 @@ impl Route {
     fn is_internet_route(&self) -> bool {
         self.destination_prefix_ip.is_unspecified() && self.destination_prefix_len == 0
     }
+
+    /// Whether the address is within the destination prefix
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let prefix = u32::from(self.destination_prefix_len);
+        match (self.destination_prefix_ip, address) {
+            (IpAddr::V4(destination), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(destination) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(destination), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(destination) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether any address is within both destination prefixes
+    pub fn overlaps(&self, other: &Route) -> bool {
+        self.contains(other.destination_prefix_ip) || other.contains(self.destination_prefix_ip)
+    }
 }
 
 /// Returns a list of IPv4 and IPv6 routes
-fn get_routes() -> Result<Vec<Route>, Error> {
+pub fn get_routes() -> Result<Vec<Route>, Error> {
+    unsafe { with_route_table(|rows| rows.iter().map(|row| route_from_row(row)).collect()) }
+}
+
+/// Changes the route metric of a route, returning false if the route no longer exists
+pub fn set_route_metric(route: &Route, metric: u32) -> Result<bool, Error> {
     unsafe {
-        let mut ptr = null_mut::<MIB_IPFORWARD_TABLE2>();
-        GetIpForwardTable2(AF_UNSPEC.0 as u16, &mut ptr).map_err(Error::GetIpForwardTable2)?;
-        let deref = &*ptr;
-        let table = slice_from_raw_parts(
-            &deref.Table as *const MIB_IPFORWARD_ROW2,
-            deref.NumEntries as usize,
-        );
-        let table = &*table;
-        let res = (0..deref.NumEntries)
-            .map(|idx| &table[idx as usize])
-            .map(|row| Route {
-                interface_index: row.InterfaceIndex,
-                destination_prefix_ip: row.DestinationPrefix.Prefix.to_std_socket_addr().ip(),
-                destination_prefix_len: row.DestinationPrefix.PrefixLength,
-            })
-            .collect::<Vec<_>>();
-        FreeMibTable(transmute(ptr));
-        Ok(res)
+        let row = with_route_table(|rows| {
+            rows.iter()
+                .find(|row| route_from_row(row) == *route)
+                .copied()
+        })?;
+        let Some(mut row) = row else {
+            return Ok(false);
+        };
+        row.Metric = metric;
+        SetIpForwardEntry2(&row).map_err(Error::SetIpForwardEntry2)?;
+        Ok(true)
+    }
+}
+
+/// Calls the function with the IPv4 and IPv6 route table
+unsafe fn with_route_table<T>(f: impl FnOnce(&[MIB_IPFORWARD_ROW2]) -> T) -> Result<T, Error> {
+    let mut ptr = null_mut::<MIB_IPFORWARD_TABLE2>();
+    GetIpForwardTable2(AF_UNSPEC.0 as u16, &mut ptr).map_err(Error::GetIpForwardTable2)?;
+    let deref = &*ptr;
+    let table = slice_from_raw_parts(
+        &deref.Table as *const MIB_IPFORWARD_ROW2,
+        deref.NumEntries as usize,
+    );
+    let res = f(&*table);
+    FreeMibTable(transmute(ptr));
+    Ok(res)
+}
+
+unsafe fn route_from_row(row: &MIB_IPFORWARD_ROW2) -> Route {
+    Route {
+        interface_index: row.InterfaceIndex,
+        destination_prefix_ip: row.DestinationPrefix.Prefix.to_std_socket_addr().ip(),
+        destination_prefix_len: row.DestinationPrefix.PrefixLength,
+        next_hop: row.NextHop.to_std_socket_addr().ip(),
+        metric: row.Metric,
     }
 }
 
-#[derive(Debug)]
-struct Adapter {
+/// Descriptions of VPN adapters that report an ordinary interface type, such as Ethernet
+const VPN_DESCRIPTIONS: &[&str] = &[
+    "anyconnect",
+    "globalprotect",
+    "pangp",
+    "zscaler",
+    "wireguard",
+    "wintun",
+    "openvpn",
+    "tap-windows",
+    "fortinet",
+    "forticlient",
+    "juniper",
+    "pulse secure",
+];
+
+/// Descriptions of the tunnel adapters Windows uses for IPv6 transition, which aren't VPNs
+const TRANSITION_TUNNELS: &[&str] = &["teredo", "6to4", "isatap", "ip-https"];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Adapter {
+    friendly_name: String,
+    description: String,
+    /// The adapter GUID
+    adapter_name: String,
+    if_type: u32,
+    oper_status: OperStatus,
     ipv4_metric: u32,
     ipv6_metric: u32,
     ipv4_interface_index: u32,
@@ -65,7 +141,7 @@ struct Adapter {
 }
 
 /// Returns a list of the system network adapters
-fn get_adapters() -> Result<Vec<Adapter>, Error> {
+pub fn get_adapters() -> Result<Vec<Adapter>, Error> {
     unsafe {
         let mut length = 0;
         let e = WIN32_ERROR(GetAdaptersAddresses(
@@ -114,7 +190,19 @@ fn get_adapters() -> Result<Vec<Adapter>, Error> {
                 dns_suffixes.push(String::from_wchar_lossy(&suffix.String));
                 next_suffix = suffix.Next;
             }
+            let adapter_name = if adapter.AdapterName.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(adapter.AdapterName.0 as *const _)
+                    .to_string_lossy()
+                    .into_owned()
+            };
             out.push(Adapter {
+                friendly_name: String::from_pwstr_lossy(adapter.FriendlyName),
+                description: String::from_pwstr_lossy(adapter.Description),
+                adapter_name,
+                if_type: adapter.IfType,
+                oper_status: OperStatus::from_raw(adapter.OperStatus.0),
                 ipv4_metric: adapter.Ipv4Metric,
                 ipv6_metric: adapter.Ipv6Metric,
                 ipv4_interface_index: adapter.Anonymous1.Anonymous.IfIndex,
@@ -131,8 +219,95 @@ fn get_adapters() -> Result<Vec<Adapter>, Error> {
 }
 
 impl Adapter {
+    pub fn dns_suffixes(&self) -> &[String] {
+        &self.dns_suffixes
+    }
+
+    pub fn friendly_name(&self) -> &str {
+        &self.friendly_name
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.oper_status == OperStatus::Up
+    }
+
+    /// The IPv4 interface index and metric, if IPv4 is enabled
+    pub fn ipv4_interface(&self) -> Option<(u32, u32)> {
+        self.ipv4_enabled
+            .then_some((self.ipv4_interface_index, self.ipv4_metric))
+    }
+
+    /// The IPv6 interface index and metric, if IPv6 is enabled
+    pub fn ipv6_interface(&self) -> Option<(u32, u32)> {
+        self.ipv6_enabled
+            .then_some((self.ipv6_interface_index, self.ipv6_metric))
+    }
+
+    pub fn adapter_type(&self) -> AdapterType {
+        AdapterType::from_if_type(self.if_type)
+    }
+
+    /// Whether the adapter matches every property specified by the rule, names and
+    /// descriptions match if they contain the rule text (ignoring case)
+    pub fn matches(&self, rule: &AdapterRule) -> bool {
+        let contains =
+            |value: &str, pattern: &str| value.to_lowercase().contains(&pattern.to_lowercase());
+        let guid = |value: &str| value.trim_matches(|c| c == '{' || c == '}').to_lowercase();
+        if rule.is_empty() {
+            return false;
+        }
+        let checks = [
+            rule.name
+                .as_ref()
+                .map(|name| contains(&self.friendly_name, name)),
+            rule.description
+                .as_ref()
+                .map(|description| contains(&self.description, description)),
+            rule.guid
+                .as_ref()
+                .map(|g| guid(g) == guid(&self.adapter_name)),
+            rule.r#type.map(|t| t == self.adapter_type()),
+        ];
+        checks.into_iter().flatten().all(|matched| matched)
+    }
+
+    /// Whether this is a connected VPN adapter, judged by its type or description
+    pub fn is_vpn(&self) -> bool {
+        let description = self.description.to_lowercase();
+        let is_tunnel = match self.adapter_type() {
+            AdapterType::Ppp => true,
+            AdapterType::Tunnel => !TRANSITION_TUNNELS.iter().any(|t| description.contains(t)),
+            _ => false,
+        };
+        self.is_up() && (is_tunnel || VPN_DESCRIPTIONS.iter().any(|d| description.contains(d)))
+    }
+
+    /// Whether any of the routes to the internet use this adapter
+    pub fn has_internet_route(&self, routes: &[Route]) -> bool {
+        routes
+            .iter()
+            .filter(|route| route.is_internet_route())
+            .any(|route| match route.destination_prefix_ip {
+                IpAddr::V4(_) => route.interface_index == self.ipv4_interface_index,
+                IpAddr::V6(_) => route.interface_index == self.ipv6_interface_index,
+            })
+    }
+
+    pub fn dns_servers(&self) -> &[IpAddr] {
+        &self.dns_servers
+    }
+
+    /// The interface index of whichever of IPv4/IPv6 is enabled
+    fn interface_index(&self) -> u32 {
+        if self.ipv4_enabled {
+            self.ipv4_interface_index
+        } else {
+            self.ipv6_interface_index
+        }
+    }
+
     // For the purposes of DNS, the interface metric is whichever one is lowest
-    fn interface_metric(&self) -> u32 {
+    pub fn interface_metric(&self) -> u32 {
         // When IPv4/IPv6 is disabled then Windows returns a metric of 0 which isn't
         // very helpful
         let ipv4 = if self.ipv4_enabled {
@@ -149,6 +324,194 @@ impl Adapter {
     }
 }
 
+/// Broad categories of the interface types reported by Windows
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdapterType {
+    Ethernet,
+    Wifi,
+    Ppp,
+    Loopback,
+    Tunnel,
+    Virtual,
+    Mobile,
+    Other,
+}
+
+impl AdapterType {
+    /// Maps the IANA interface type (IF_TYPE_*)
+    fn from_if_type(if_type: u32) -> Self {
+        match if_type {
+            6 => AdapterType::Ethernet,
+            71 => AdapterType::Wifi,
+            23 => AdapterType::Ppp,
+            24 => AdapterType::Loopback,
+            131 => AdapterType::Tunnel,
+            53 => AdapterType::Virtual,
+            243 | 244 => AdapterType::Mobile,
+            _ => AdapterType::Other,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperStatus {
+    Up,
+    Down,
+    Testing,
+    Unknown,
+    Dormant,
+    NotPresent,
+    LowerLayerDown,
+}
+
+impl OperStatus {
+    /// Maps the IF_OPER_STATUS value
+    fn from_raw(status: i32) -> Self {
+        match status {
+            1 => OperStatus::Up,
+            2 => OperStatus::Down,
+            3 => OperStatus::Testing,
+            5 => OperStatus::Dormant,
+            6 => OperStatus::NotPresent,
+            7 => OperStatus::LowerLayerDown,
+            _ => OperStatus::Unknown,
+        }
+    }
+}
+
+/// The routes and adapters, used to tell when the network has stopped changing
+#[derive(Debug, PartialEq)]
+pub struct NetworkSnapshot {
+    routes: Vec<Route>,
+    adapters: Vec<Adapter>,
+}
+
+impl NetworkSnapshot {
+    pub fn capture() -> Result<Self, Error> {
+        Ok(Self {
+            routes: get_routes()?,
+            adapters: get_adapters()?,
+        })
+    }
+}
+
+/// The parts of the network that affect DNS: the adapters in order of priority, and the
+/// configuration generated from them
+///
+/// Route notifications also fire for changes that don't matter, such as WSL's own vEthernet
+/// adapter, so this is compared with the last applied snapshot to decide whether to update
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DnsSnapshot {
+    adapters: Vec<RankedAdapter>,
+    dns: DnsConfiguration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct RankedAdapter {
+    interface_index: u32,
+    metric: u32,
+    dns_servers: Vec<IpAddr>,
+    dns_suffixes: Vec<String>,
+}
+
+impl DnsSnapshot {
+    /// `adapters` should already be ordered by priority, as from `get_internet_adapters()`
+    pub fn new(adapters: &[Adapter], dns: &DnsConfiguration) -> Self {
+        let adapters = adapters
+            .iter()
+            .map(|adapter| RankedAdapter {
+                interface_index: adapter.interface_index(),
+                metric: adapter.interface_metric(),
+                dns_servers: adapter.dns_servers.clone(),
+                dns_suffixes: adapter.dns_suffixes.clone(),
+            })
+            .collect();
+        Self {
+            adapters,
+            dns: dns.clone(),
+        }
+    }
+
+    /// Describes each difference from the previous snapshot
+    pub fn diff(&self, previous: &DnsSnapshot) -> Vec<String> {
+        let mut changes = vec![];
+        for adapter in &self.adapters {
+            let index = adapter.interface_index;
+            let Some(old) = previous
+                .adapters
+                .iter()
+                .find(|a| a.interface_index == index)
+            else {
+                changes.push(format!(
+                    "adapter {index} added with metric {}",
+                    adapter.metric
+                ));
+                continue;
+            };
+            if old.metric != adapter.metric {
+                changes.push(format!(
+                    "adapter {index} metric {} -> {}",
+                    old.metric, adapter.metric
+                ));
+            }
+            if old.dns_servers != adapter.dns_servers {
+                changes.push(format!(
+                    "adapter {index} DNS servers [{}] -> [{}]",
+                    old.dns_servers.iter().join(", "),
+                    adapter.dns_servers.iter().join(", ")
+                ));
+            }
+            if old.dns_suffixes != adapter.dns_suffixes {
+                changes.push(format!(
+                    "adapter {index} suffixes [{}] -> [{}]",
+                    old.dns_suffixes.join(", "),
+                    adapter.dns_suffixes.join(", ")
+                ));
+            }
+        }
+        for old in &previous.adapters {
+            if !self
+                .adapters
+                .iter()
+                .any(|a| a.interface_index == old.interface_index)
+            {
+                changes.push(format!("adapter {} removed", old.interface_index));
+            }
+        }
+        let order = |s: &DnsSnapshot| s.adapters.iter().map(|a| a.interface_index).collect_vec();
+        if changes.is_empty() && order(self) != order(previous) {
+            changes.push(format!(
+                "adapter order [{}] -> [{}]",
+                order(previous).iter().join(", "),
+                order(self).iter().join(", ")
+            ));
+        }
+        if self.dns.networking_mode != previous.dns.networking_mode {
+            changes.push(format!(
+                "networking mode {:?} -> {:?}",
+                previous.dns.networking_mode, self.dns.networking_mode
+            ));
+        }
+        if self.dns.servers != previous.dns.servers {
+            changes.push(format!(
+                "DNS servers [{}] -> [{}]",
+                previous.dns.servers.iter().join(", "),
+                self.dns.servers.iter().join(", ")
+            ));
+        }
+        if self.dns.suffixes != previous.dns.suffixes {
+            changes.push(format!(
+                "search suffixes [{}] -> [{}]",
+                previous.dns.suffixes.join(", "),
+                self.dns.suffixes.join(", ")
+            ));
+        }
+        changes
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Calls to GetAdaptersAddresses() returned different buffer sizes")]
@@ -157,64 +520,160 @@ pub enum Error {
     GetIpForwardTable2(#[source] windows::core::Error),
     #[error("Call to GetAdaptersAddresses() failed: {0}")]
     GetAdaptersAddresses(#[source] windows::core::Error),
+    #[error("Call to SetIpForwardEntry2() failed: {0}")]
+    SetIpForwardEntry2(#[source] windows::core::Error),
+}
+
+impl Error {
+    /// The adapters can change between the calls to GetAdaptersAddresses(), e.g. while a VPN is
+    /// connecting, in which case trying again is likely to succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::GetAdaptersAddressesOverflow)
+    }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
 pub struct DnsConfiguration {
     servers: Vec<IpAddr>,
     suffixes: Vec<String>,
+    /// Determines which of the servers can be used from WSL
+    networking_mode: NetworkingMode,
 }
 
-pub fn get_configuration() -> Result<DnsConfiguration, Error> {
-    // List of routes to the internet
-    let internet_routes = get_routes()?
-        .into_iter()
-        .filter(Route::is_internet_route)
-        .collect::<Vec<_>>();
+/// Link local addresses can't be used without a zone, and Windows lists the deprecated site local
+/// addresses fec0:0:0:ffff::1-3 when there are no IPv6 DNS servers
+fn is_scoped(ip: &Ipv6Addr) -> bool {
+    matches!(ip.segments()[0] & 0xffc0, 0xfe80 | 0xfec0)
+}
+
+/// Returns the adapters that WSL can reach DNS servers through (or are included by the rules),
+/// ordered by DNS priority
+pub fn get_internet_adapters(
+    setting: &AdapterSetting,
+    networking_mode: NetworkingMode,
+) -> Result<Vec<Adapter>, Error> {
+    let adapters = select_adapters(get_adapters()?, &get_routes()?, setting, networking_mode);
+    log::info!("Found adapters: {:?}", adapters);
+    Ok(adapters)
+}
 
-    // DNS priority is determined by interface metric
-    // However we also want to exclude various system adapters such as WSL
-    // so we will filter out any adapters that don't have a route to the internet
-    let internet_adapters = get_adapters()?
+fn select_adapters(
+    adapters: Vec<Adapter>,
+    routes: &[Route],
+    setting: &AdapterSetting,
+    networking_mode: NetworkingMode,
+) -> Vec<Adapter> {
+    let reachable = |adapter: &Adapter| match networking_mode {
+        // The distributions share the Windows interfaces, so the DNS servers of any connected
+        // adapter can be reached, even without a route to the internet (e.g. a split tunnel VPN).
+        // Loopback and Hyper-V switches such as "vEthernet (Default Switch)" aren't mirrored
+        NetworkingMode::Mirrored => {
+            adapter.is_up()
+                && !adapter.dns_servers.is_empty()
+                && adapter.adapter_type() != AdapterType::Loopback
+                && !adapter.friendly_name.starts_with("vEthernet (")
+        }
+        // DNS priority is determined by interface metric (unless configured otherwise)
+        // However we also want to exclude various system adapters such as WSL
+        // so we will filter out any adapters that don't have a route to the internet
+        _ => adapter.has_internet_route(routes),
+    };
+    adapters
         .into_iter()
         .filter(|adapter| {
-            internet_routes
-                .iter()
-                .any(|route| match route.destination_prefix_ip {
-                    IpAddr::V4(_) => route.interface_index == adapter.ipv4_interface_index,
-                    IpAddr::V6(_) => route.interface_index == adapter.ipv6_interface_index,
-                })
+            setting.include.iter().any(|rule| adapter.matches(rule)) || reachable(adapter)
         })
-        .sorted_by_key(Adapter::interface_metric)
-        .collect::<Vec<_>>();
-    log::info!("Found adapters: {:?}", internet_adapters);
-
-    let servers = internet_adapters
-        .iter()
-        .flat_map(|adapter| adapter.dns_servers.clone())
-        .unique()
-        .collect::<Vec<_>>();
-    let suffixes = internet_adapters
-        .iter()
-        .flat_map(|adapter| adapter.dns_suffixes.clone())
-        .unique()
-        .collect::<Vec<_>>();
-
-    Ok(DnsConfiguration { servers, suffixes })
+        .filter(|adapter| {
+            let excluded = setting.exclude.iter().any(|rule| adapter.matches(rule));
+            if excluded {
+                log::info!("Excluding adapter: {}", adapter.friendly_name);
+            }
+            !excluded
+        })
+        .filter_map(|adapter| {
+            let preferred = setting.prefer.iter().position(|rule| adapter.matches(rule));
+            if preferred.is_none() && setting.ranking == AdapterRanking::ExplicitOrder {
+                log::info!(
+                    "Ignoring adapter without a prefer rule: {}",
+                    adapter.friendly_name
+                );
+                return None;
+            }
+            Some((preferred, adapter))
+        })
+        // Preferred adapters come first, in the order of the rules
+        .sorted_by_key(|(preferred, adapter)| {
+            let vpn_first = setting.ranking == AdapterRanking::VpnFirst && adapter.is_vpn();
+            (
+                preferred.unwrap_or(setting.prefer.len()),
+                !vpn_first,
+                adapter.interface_metric(),
+            )
+        })
+        .map(|(_, adapter)| adapter)
+        .collect()
 }
 
 impl DnsConfiguration {
-    pub fn generate_resolv(&self) -> String {
-        let date = chrono::Local::now();
-        let date = format!("{}", date.format("%Y-%m-%d %H:%M:%S"));
-        let mut lines = vec![format!("# Generated by {APP_NAME} at {date}")];
-        // WSL2 doesn't currently support IPv6 - but might do in future?
-        // https://github.com/microsoft/WSL/issues/4518
+    /// Combines the DNS servers and suffixes of the adapters, in order of priority
+    pub fn from_adapters(adapters: &[Adapter], networking_mode: NetworkingMode) -> Self {
+        let servers = adapters
+            .iter()
+            .flat_map(|adapter| adapter.dns_servers.clone())
+            .unique()
+            .collect::<Vec<_>>();
+        let suffixes = adapters
+            .iter()
+            .flat_map(|adapter| adapter.dns_suffixes.clone())
+            .unique()
+            .collect::<Vec<_>>();
+        DnsConfiguration {
+            servers,
+            suffixes,
+            networking_mode,
+        }
+    }
+
+    pub fn servers(&self) -> &[IpAddr] {
+        &self.servers
+    }
+
+    /// Moves unreachable servers to the end of the list, or removes them entirely
+    pub fn prioritise_reachable(&mut self, unreachable: &[IpAddr], remove: bool) {
+        let (reachable, unreachable): (Vec<_>, Vec<_>) = std::mem::take(&mut self.servers)
+            .into_iter()
+            .partition(|server| !unreachable.contains(server));
+        self.servers = reachable;
+        if !remove {
+            self.servers.extend(unreachable);
+        }
+    }
+
+    /// The servers that will be written to resolv.conf
+    pub fn resolv_servers(&self) -> impl Iterator<Item = &IpAddr> {
         // resolv.conf typically only allows up to 3 nameservers
         self.servers
             .iter()
-            .filter(|server| server.is_ipv4())
+            .filter(move |server| self.is_usable(server))
             .take(3)
+    }
+
+    /// With NAT, WSL2 doesn't support IPv6 (https://github.com/microsoft/WSL/issues/4518) and
+    /// has its own loopback. When mirrored it shares the Windows interfaces, including loopback
+    fn is_usable(&self, server: &IpAddr) -> bool {
+        match (self.networking_mode, server) {
+            (NetworkingMode::Mirrored, IpAddr::V4(_)) => true,
+            (NetworkingMode::Mirrored, IpAddr::V6(ip)) => !is_scoped(ip),
+            (_, IpAddr::V4(ip)) => !ip.is_loopback(),
+            (_, IpAddr::V6(_)) => false,
+        }
+    }
+
+    pub fn generate_resolv(&self) -> String {
+        let date = chrono::Local::now();
+        let date = format!("{}", date.format("%Y-%m-%d %H:%M:%S"));
+        let mut lines = vec![format!("# Generated by {APP_NAME} at {date}")];
+        self.resolv_servers()
             .for_each(|server| lines.push(format!("nameserver {}", server)));
 
         if !self.suffixes.is_empty() {
@@ -224,4 +683,243 @@ impl DnsConfiguration {
         lines.push(String::new());
         lines.join("\n")
     }
+
+    /// Summarises the configuration, including anything that was removed since the
+    /// previous configuration
+    pub fn describe_changes(&self, previous: Option<&DnsConfiguration>) -> String {
+        let servers = self.resolv_servers().collect::<Vec<_>>();
+        let mut parts = vec![if servers.is_empty() {
+            "No DNS servers".to_string()
+        } else {
+            format!("DNS servers: {}", servers.iter().join(", "))
+        }];
+        if !self.suffixes.is_empty() {
+            parts.push(format!("search {}", self.suffixes.join(" ")));
+        }
+        if let Some(previous) = previous {
+            let removed = previous
+                .resolv_servers()
+                .filter(|s| !servers.contains(s))
+                .map(ToString::to_string)
+                .chain(
+                    previous
+                        .suffixes
+                        .iter()
+                        .filter(|s| !self.suffixes.contains(s))
+                        .cloned(),
+                )
+                .collect::<Vec<_>>();
+            if !removed.is_empty() {
+                parts.push(format!("no longer using {}", removed.join(", ")));
+            }
+        }
+        parts.join("; ")
+    }
+}
+
+#[cfg(test)]
+impl Adapter {
+    /// A connected Ethernet adapter with only IPv4 enabled
+    pub fn test(name: &str, description: &str, index: u32, metric: u32, dns: &[&str]) -> Self {
+        Self {
+            friendly_name: name.to_string(),
+            description: description.to_string(),
+            adapter_name: format!("{{00000000-0000-0000-0000-{index:012}}}"),
+            if_type: 6,
+            oper_status: OperStatus::Up,
+            ipv4_metric: metric,
+            ipv6_metric: 0,
+            ipv4_interface_index: index,
+            ipv6_interface_index: index,
+            dns_servers: dns.iter().map(|s| s.parse().unwrap()).collect(),
+            dns_suffixes: vec![],
+            ipv4_enabled: true,
+            ipv6_enabled: false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Route {
+    /// A route to the prefix, given as an address and length such as "10.0.0.0/8"
+    pub fn test(interface_index: u32, prefix: &str, next_hop: &str, metric: u32) -> Self {
+        let (ip, len) = prefix.split_once('/').unwrap();
+        Self {
+            interface_index,
+            destination_prefix_ip: ip.parse().unwrap(),
+            destination_prefix_len: len.parse().unwrap(),
+            next_hop: next_hop.parse().unwrap(),
+            metric,
+        }
+    }
+}
+
+/// Interface indexes for the adapters and routes used by tests
+#[cfg(test)]
+pub mod test_index {
+    pub const WSL: u32 = 10;
+    pub const VPN: u32 = 20;
+    pub const ETHERNET: u32 = 30;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_index::{ETHERNET, VPN, WSL};
+    use super::*;
+
+    fn configuration(servers: &[&str]) -> DnsConfiguration {
+        DnsConfiguration {
+            servers: servers.iter().map(|s| s.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn route(interface_index: u32, prefix: &str) -> Route {
+        Route::test(interface_index, prefix, "0.0.0.0", 0)
+    }
+
+    /// Ethernet with a route to the internet, a split tunnel VPN, WSL's NAT adapter, a Hyper-V
+    /// switch, and loopback
+    fn adapters() -> Vec<Adapter> {
+        let mut loopback = Adapter::test("Loopback", "Loopback", 1, 75, &["127.0.0.1"]);
+        loopback.if_type = 24;
+        vec![
+            Adapter::test("Ethernet", "Intel Ethernet", ETHERNET, 25, &["192.168.1.1"]),
+            Adapter::test("VPN", "Cisco AnyConnect", VPN, 1, &["10.0.0.53"]),
+            Adapter::test("vEthernet (WSL)", "Hyper-V", WSL, 15, &[]),
+            Adapter::test(
+                "vEthernet (Default Switch)",
+                "Hyper-V",
+                40,
+                15,
+                &["172.30.0.1"],
+            ),
+            loopback,
+        ]
+    }
+
+    fn select(toml: &str, networking_mode: NetworkingMode) -> Vec<String> {
+        let routes = [route(ETHERNET, "0.0.0.0/0"), route(VPN, "10.0.0.0/8")];
+        let setting = toml::from_str(toml).unwrap();
+        select_adapters(adapters(), &routes, &setting, networking_mode)
+            .into_iter()
+            .map(|a| a.friendly_name)
+            .collect()
+    }
+
+    #[test]
+    fn selects_adapters_with_an_internet_route_with_nat() {
+        assert_eq!(select("", NetworkingMode::Nat), ["Ethernet"]);
+        assert_eq!(
+            select("include = [{ name = \"VPN\" }]", NetworkingMode::Nat),
+            ["VPN", "Ethernet"]
+        );
+    }
+
+    #[test]
+    fn selects_connected_adapters_with_dns_servers_when_mirrored() {
+        assert_eq!(select("", NetworkingMode::Mirrored), ["VPN", "Ethernet"]);
+        assert_eq!(
+            select("exclude = [{ name = \"VPN\" }]", NetworkingMode::Mirrored),
+            ["Ethernet"]
+        );
+    }
+
+    #[test]
+    fn orders_adapters_by_the_prefer_rules() {
+        let prefer = "prefer = [{ name = \"Ethernet\" }]";
+        assert_eq!(
+            select(prefer, NetworkingMode::Mirrored),
+            ["Ethernet", "VPN"]
+        );
+        let explicit = format!("ranking = \"explicit_order\"\n{prefer}");
+        assert_eq!(select(&explicit, NetworkingMode::Mirrored), ["Ethernet"]);
+    }
+
+    #[test]
+    fn orders_vpn_adapters_first() {
+        // The VPN has a higher metric than Ethernet, so would be last when ranked by metric
+        let adapters = vec![
+            Adapter::test("Ethernet", "Intel Ethernet", 30, 5, &["192.168.1.1"]),
+            Adapter::test("VPN", "Cisco AnyConnect", 20, 50, &["10.0.0.53"]),
+            Adapter::test("Wi-Fi", "Intel Wireless", 40, 35, &["192.168.2.1"]),
+        ];
+        let routes = [
+            route(30, "0.0.0.0/0"),
+            route(20, "0.0.0.0/0"),
+            route(40, "0.0.0.0/0"),
+        ];
+        let select = |toml: &str| {
+            let setting = toml::from_str(toml).unwrap();
+            select_adapters(adapters.clone(), &routes, &setting, NetworkingMode::Nat)
+                .into_iter()
+                .map(|a| a.friendly_name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(select(""), ["Ethernet", "Wi-Fi", "VPN"]);
+        assert_eq!(
+            select("ranking = \"vpn_first\""),
+            ["VPN", "Ethernet", "Wi-Fi"]
+        );
+        // Prefer rules still come first
+        assert_eq!(
+            select("ranking = \"vpn_first\"\nprefer = [{ name = \"Wi-Fi\" }]"),
+            ["Wi-Fi", "VPN", "Ethernet"]
+        );
+    }
+
+    #[test]
+    fn detects_vpn_adapters_by_type() {
+        for if_type in [131, 23] {
+            let mut vpn = Adapter::test("VPN", "Generic Adapter", 20, 1, &[]);
+            vpn.if_type = if_type;
+            assert!(vpn.is_vpn(), "{if_type}");
+            vpn.oper_status = OperStatus::Down;
+            assert!(!vpn.is_vpn(), "{if_type}");
+        }
+    }
+
+    #[test]
+    fn detects_vpn_adapters_by_description() {
+        for description in [
+            "Cisco AnyConnect Secure Mobility Client Virtual Miniport Adapter",
+            "PANGP Virtual Ethernet Adapter",
+            "TAP-Windows Adapter V9",
+            "WireGuard Tunnel",
+        ] {
+            let vpn = Adapter::test("VPN", description, 20, 1, &[]);
+            assert!(vpn.is_vpn(), "{description}");
+        }
+        let ethernet = Adapter::test("Ethernet", "Intel(R) Ethernet Connection", 30, 25, &[]);
+        assert!(!ethernet.is_vpn());
+    }
+
+    #[test]
+    fn transition_tunnels_are_not_vpns() {
+        for description in [
+            "Microsoft Teredo Tunneling Adapter",
+            "Microsoft 6to4 Adapter",
+            "Microsoft ISATAP Adapter",
+            "Microsoft IP-HTTPS Platform Adapter",
+        ] {
+            let mut tunnel = Adapter::test("Tunnel", description, 50, 1, &[]);
+            tunnel.if_type = 131;
+            assert!(!tunnel.is_vpn(), "{description}");
+        }
+    }
+
+    #[test]
+    fn demotes_unreachable_servers() {
+        let mut dns = configuration(&["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+        dns.prioritise_reachable(&["10.0.0.1".parse().unwrap()], false);
+        assert_eq!(dns, configuration(&["10.0.0.2", "10.0.0.3", "10.0.0.1"]));
+    }
+
+    #[test]
+    fn removes_unreachable_servers() {
+        let mut dns = configuration(&["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+        let unreachable = ["10.0.0.1".parse().unwrap(), "10.0.0.3".parse().unwrap()];
+        dns.prioritise_reachable(&unreachable, true);
+        assert_eq!(dns, configuration(&["10.0.0.2"]));
+    }
 }
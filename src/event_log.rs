@@ -0,0 +1,173 @@
+use crate::diagnostics::Finding;
+use crate::dns::{Adapter, DnsConfiguration};
+use crate::health::HealthReport;
+use crate::metrics::MetricChange;
+use crate::probe::ProbeResult;
+use crate::request::{RunReason, Targets};
+use crate::routing::RouteConflict;
+use crate::wsl;
+use serde::Serialize;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Start a new event log once the current one reaches this size
+const MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// A JSON-lines file containing a structured record of every run
+pub struct EventLog {
+    path: PathBuf,
+}
+
+impl EventLog {
+    pub fn new(directory: &Path) -> Self {
+        Self {
+            path: directory.join("events.jsonl"),
+        }
+    }
+
+    pub fn write(&self, record: &RunRecord) {
+        if let Err(e) = self.try_write(record) {
+            log::error!("Failed to write event log: {e}");
+        }
+    }
+
+    fn try_write(&self, record: &RunRecord) -> std::io::Result<()> {
+        if fs::metadata(&self.path)
+            .map(|m| m.len())
+            .unwrap_or_default()
+            >= MAX_SIZE
+        {
+            fs::rename(&self.path, self.path.with_extension("1.jsonl"))?;
+        }
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())
+    }
+}
+
+/// A record of a single run, and the actions taken for each distribution
+#[derive(Debug, Serialize)]
+pub struct RunRecord {
+    pub timestamp: String,
+    pub trigger: RunReason,
+    pub targets: Targets,
+    pub debounced: usize,
+    /// Interface metrics that were changed before the run
+    pub metric_changes: Vec<MetricChange>,
+    /// Routes that conflict with the WSL subnet
+    pub route_conflicts: Vec<RouteConflict>,
+    /// Misconfiguration of the network
+    pub findings: Vec<Finding>,
+    pub adapters: Vec<Adapter>,
+    pub probes: Vec<ProbeResult>,
+    pub dns: Option<DnsConfiguration>,
+    /// Differences from the last applied snapshot that caused a network change to run
+    pub changes: Vec<String>,
+    /// Whether the run was skipped because nothing relevant to DNS changed, or there were no DNS
+    /// servers
+    pub skipped: bool,
+    pub calls: Calls,
+    pub distributions: Vec<DistributionRecord>,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+    #[serde(skip)]
+    start: Instant,
+}
+
+impl RunRecord {
+    pub fn new(trigger: RunReason, targets: Targets, debounced: usize) -> Self {
+        Self {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            trigger,
+            targets,
+            debounced,
+            metric_changes: vec![],
+            route_conflicts: vec![],
+            findings: vec![],
+            adapters: vec![],
+            probes: vec![],
+            dns: None,
+            changes: vec![],
+            skipped: false,
+            calls: Default::default(),
+            distributions: vec![],
+            duration_ms: 0,
+            error: None,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn finish<T, E: ToString>(&mut self, result: &Result<T, E>) {
+        self.duration_ms = self.start.elapsed().as_millis() as u64;
+        self.error = result.as_ref().err().map(ToString::to_string);
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DistributionAction {
+    Ignored,
+    Unchanged,
+    Updated,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DistributionRecord {
+    pub name: String,
+    pub action: DistributionAction,
+    pub calls: Calls,
+    pub health: Option<HealthReport>,
+    /// A problem that didn't stop the distribution from being updated
+    pub warning: Option<String>,
+    pub error: Option<String>,
+}
+
+impl DistributionRecord {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            action: DistributionAction::Ignored,
+            calls: Default::default(),
+            health: None,
+            warning: None,
+            error: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CallRecord {
+    pub command: String,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+/// The calls to wsl.exe made during a run, and how long each took
+#[derive(Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct Calls(Vec<CallRecord>);
+
+impl Calls {
+    pub fn time<T>(
+        &mut self,
+        command: &str,
+        f: impl FnOnce() -> Result<T, wsl::Error>,
+    ) -> Result<T, wsl::Error> {
+        let start = Instant::now();
+        let result = f();
+        self.0.push(CallRecord {
+            command: command.to_string(),
+            duration_ms: start.elapsed().as_millis() as u64,
+            error: result.as_ref().err().map(wsl::Error::summary),
+        });
+        result
+    }
+}
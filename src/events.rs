@@ -0,0 +1,226 @@
+use crate::config::EventSetting;
+use crate::dns::NetworkSnapshot;
+use crate::request::{RunReason, RunRequest};
+#[cfg(windows)]
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+use thiserror::Error;
+#[cfg(windows)]
+use windows::Win32::Foundation::{BOOLEAN, HANDLE};
+#[cfg(windows)]
+use windows::Win32::NetworkManagement::IpHelper::{
+    CancelMibChangeNotify2, NotifyIpInterfaceChange, NotifyRouteChange2,
+    NotifyUnicastIpAddressChange, MIB_IPFORWARD_ROW2, MIB_IPINTERFACE_ROW, MIB_NOTIFICATION_TYPE,
+    MIB_UNICASTIPADDRESS_ROW,
+};
+#[cfg(windows)]
+use windows::Win32::Networking::WinSock::AF_UNSPEC;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[cfg(windows)]
+    #[error("Failed to register for {0:?} notifications: {1}")]
+    Register(Notification, #[source] windows::core::Error),
+}
+
+/// Something that requests a run when the network changes
+pub trait EventSource {
+    /// Starts sending requests to the runner, until the source is dropped
+    fn start(&mut self, tx: Sender<RunRequest>) -> Result<(), Error>;
+}
+
+/// Starts each of the enabled sources, the sources must be kept alive for as long as they are
+/// needed
+pub fn start_sources(setting: &EventSetting, tx: &Sender<RunRequest>) -> Vec<Box<dyn EventSource>> {
+    let mut sources: Vec<Box<dyn EventSource>> = vec![];
+    #[cfg(windows)]
+    {
+        sources.push(Box::new(NotificationSource::new(Notification::Route)));
+        if setting.interface_changes {
+            sources.push(Box::new(NotificationSource::new(Notification::Interface)));
+        }
+        if setting.address_changes {
+            sources.push(Box::new(NotificationSource::new(Notification::Address)));
+        }
+    }
+    if setting.poll_secs > 0 {
+        sources.push(Box::new(PollSource::new(
+            Duration::from_secs(setting.poll_secs),
+            || NetworkSnapshot::capture().ok(),
+        )));
+    }
+    sources
+        .into_iter()
+        .filter_map(|mut source| match source.start(tx.clone()) {
+            Ok(_) => Some(source),
+            Err(e) => {
+                log::error!("{e}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// The IP Helper change notifications
+#[cfg(windows)]
+#[derive(Debug, Copy, Clone)]
+pub enum Notification {
+    Route,
+    Interface,
+    Address,
+}
+
+/// Receives change notifications from Windows
+#[cfg(windows)]
+struct NotificationSource {
+    notification: Notification,
+    /// Passed to the callback, so must not be dropped until the notification is cancelled
+    context: Option<Box<Sender<RunRequest>>>,
+    handle: HANDLE,
+}
+
+#[cfg(windows)]
+impl NotificationSource {
+    fn new(notification: Notification) -> Self {
+        Self {
+            notification,
+            context: None,
+            handle: HANDLE::default(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl EventSource for NotificationSource {
+    fn start(&mut self, tx: Sender<RunRequest>) -> Result<(), Error> {
+        let context = Box::new(tx);
+        let context_ptr = context.as_ref() as *const Sender<RunRequest> as *const c_void;
+        unsafe {
+            match self.notification {
+                Notification::Route => NotifyRouteChange2(
+                    AF_UNSPEC.0 as u16,
+                    Some(route_callback),
+                    context_ptr,
+                    BOOLEAN(0),
+                    &mut self.handle,
+                ),
+                Notification::Interface => NotifyIpInterfaceChange(
+                    AF_UNSPEC.0 as u16,
+                    Some(interface_callback),
+                    context_ptr,
+                    BOOLEAN(0),
+                    &mut self.handle,
+                ),
+                Notification::Address => NotifyUnicastIpAddressChange(
+                    AF_UNSPEC.0 as u16,
+                    Some(address_callback),
+                    context_ptr,
+                    BOOLEAN(0),
+                    &mut self.handle,
+                ),
+            }
+            .map_err(|e| Error::Register(self.notification, e))?;
+        }
+        self.context = Some(context);
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+impl Drop for NotificationSource {
+    fn drop(&mut self) {
+        if self.context.is_some() {
+            unsafe {
+                CancelMibChangeNotify2(self.handle).ok();
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+unsafe fn send(context: *const c_void, reason: RunReason) {
+    let tx = &*(context as *const Sender<RunRequest>);
+    tx.send(reason.into()).ok();
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn route_callback(
+    context: *const c_void,
+    _: *const MIB_IPFORWARD_ROW2,
+    _: MIB_NOTIFICATION_TYPE,
+) {
+    send(context, RunReason::RouteChange);
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn interface_callback(
+    context: *const c_void,
+    _: *const MIB_IPINTERFACE_ROW,
+    _: MIB_NOTIFICATION_TYPE,
+) {
+    send(context, RunReason::InterfaceChange);
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn address_callback(
+    context: *const c_void,
+    _: *const MIB_UNICASTIPADDRESS_ROW,
+    _: MIB_NOTIFICATION_TYPE,
+) {
+    send(context, RunReason::AddressChange);
+}
+
+/// Periodically captures a snapshot, requesting a run if it has changed, in case any
+/// notifications are missed (e.g. a DNS server change pushed by DHCP)
+pub struct PollSource<S> {
+    interval: Duration,
+    capture: Option<Box<dyn FnMut() -> Option<S> + Send>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl<S> PollSource<S> {
+    pub fn new(interval: Duration, capture: impl FnMut() -> Option<S> + Send + 'static) -> Self {
+        Self {
+            interval,
+            capture: Some(Box::new(capture)),
+            stopped: Default::default(),
+        }
+    }
+}
+
+impl<S: PartialEq + Send + 'static> EventSource for PollSource<S> {
+    fn start(&mut self, tx: Sender<RunRequest>) -> Result<(), Error> {
+        let interval = self.interval;
+        let stopped = self.stopped.clone();
+        if let Some(mut capture) = self.capture.take() {
+            spawn(move || {
+                let mut last = capture();
+                loop {
+                    sleep(interval);
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let current = capture();
+                    if current.is_some() && current != last {
+                        log::info!("Network changed since the last poll");
+                        if tx.send(RunReason::NetworkPoll.into()).is_err() {
+                            break;
+                        }
+                    }
+                    last = current;
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<S> Drop for PollSource<S> {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::config::HealthCheckSetting;
+use crate::wsl;
+use crate::wsl::{Limits, WslDistribution};
+use serde::Serialize;
+use std::fmt::Write;
+use std::net::IpAddr;
+
+/// The outcome of looking up a test name from within a distribution
+#[derive(Debug, Serialize)]
+pub struct LookupResult {
+    pub name: String,
+    pub success: bool,
+    /// Unknown if the distribution's `date` can't measure nanoseconds (such as busybox)
+    pub latency_ms: Option<u64>,
+}
+
+/// Whether a DNS server could be reached (on TCP port 53) from within a distribution
+#[derive(Debug, Serialize)]
+pub struct ServerResult {
+    pub server: IpAddr,
+    pub reachable: bool,
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct HealthReport {
+    pub lookups: Vec<LookupResult>,
+    pub servers: Vec<ServerResult>,
+}
+
+impl HealthReport {
+    /// Every lookup succeeded, and every server could be reached. Lookups try the servers in
+    /// order, so an unreachable server delays lookups even if they succeed
+    pub fn is_healthy(&self) -> bool {
+        self.lookups.iter().all(|l| l.success) && self.servers.iter().all(|s| s.reachable)
+    }
+
+    /// Describes the failed lookups and unreachable servers
+    pub fn summary(&self) -> String {
+        let failed = self
+            .lookups
+            .iter()
+            .filter(|l| !l.success)
+            .map(|l| format!("lookup of {} failed", l.name))
+            .chain(
+                self.servers
+                    .iter()
+                    .filter(|s| !s.reachable)
+                    .map(|s| format!("{} unreachable", s.server)),
+            )
+            .collect::<Vec<_>>();
+        if failed.is_empty() {
+            "DNS is working".to_string()
+        } else {
+            failed.join(", ")
+        }
+    }
+}
+
+/// Names are substituted into a shell script, so only allow valid hostname characters
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+/// Prints the time in nanoseconds, or nothing if `date` doesn't support `%N` (busybox prints it
+/// literally), then the milliseconds elapsed since a time, or "-" if either is unknown
+const TIMING: &str = "\
+now() { t=$(date +%s%N); case \"$t\" in ''|*[!0-9]*) ;; *) echo \"$t\" ;; esac; }
+elapsed() { e=$(now); if [ -n \"$1\" ] && [ -n \"$e\" ]; then echo $(( (e - $1) / 1000000 )); else echo -; fi; }
+";
+
+/// Builds a script that times each lookup and server connection, printing one line per result
+fn build_script(servers: &[IpAddr], setting: &HealthCheckSetting) -> String {
+    let timeout = setting.timeout_secs;
+    let mut script = String::from(TIMING);
+    for name in setting.names.iter().filter(|n| is_valid_name(n)) {
+        writeln!(
+            script,
+            "s=$(now); timeout {timeout} getent hosts '{name}' >/dev/null 2>&1; r=$?; \
+             echo \"lookup {name} $r $(elapsed \"$s\")\""
+        )
+        .unwrap();
+    }
+    // Connecting to a server relies on bash's /dev/tcp support
+    script.push_str("if command -v bash >/dev/null; then\n");
+    for server in servers {
+        writeln!(
+            script,
+            "s=$(now); timeout {timeout} bash -c 'exec 3<>/dev/tcp/{server}/53' >/dev/null 2>&1; \
+             r=$?; echo \"server {server} $r $(elapsed \"$s\")\""
+        )
+        .unwrap();
+    }
+    script.push_str("fi\n");
+    script
+}
+
+fn parse_output(output: &str) -> HealthReport {
+    let mut report = HealthReport::default();
+    for line in output.lines() {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        if let [kind, target, code, latency] = parts[..] {
+            let success = code == "0";
+            let latency_ms = latency.parse().ok();
+            match kind {
+                "lookup" => report.lookups.push(LookupResult {
+                    name: target.to_string(),
+                    success,
+                    latency_ms,
+                }),
+                "server" => {
+                    if let Ok(server) = target.parse() {
+                        report.servers.push(ServerResult {
+                            server,
+                            reachable: success,
+                            latency_ms,
+                        })
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    report
+}
+
+/// Checks that DNS lookups work from within the distribution, and which servers are reachable
+pub fn check(
+    distribution: &WslDistribution,
+    limits: &Limits,
+    servers: &[IpAddr],
+    setting: &HealthCheckSetting,
+) -> Result<HealthReport, wsl::Error> {
+    for name in setting.names.iter().filter(|n| !is_valid_name(n)) {
+        log::warn!("Skipping invalid health check name: {name:?}");
+    }
+    let output = distribution.run_script(limits, &build_script(servers, setting))?;
+    let report = parse_output(&output);
+    log::info!("DNS health check for {}: {:?}", distribution.name, report);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting(names: &[&str]) -> HealthCheckSetting {
+        HealthCheckSetting {
+            enabled: true,
+            names: names.iter().map(|n| n.to_string()).collect(),
+            timeout_secs: 3,
+        }
+    }
+
+    #[test]
+    fn validates_names() {
+        assert!(is_valid_name("github.com"));
+        assert!(is_valid_name("_ldap._tcp.corp-1.example"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("github.com'; reboot; '"));
+        assert!(!is_valid_name("$(id)"));
+        assert!(!is_valid_name("a b"));
+    }
+
+    #[test]
+    fn builds_a_line_per_valid_name_and_server() {
+        let servers = ["10.0.0.1".parse().unwrap(), "fd00::1".parse().unwrap()];
+        let script = build_script(&servers, &setting(&["github.com", "bad name"]));
+        assert!(script.starts_with(TIMING));
+        assert!(script.contains("timeout 3 getent hosts 'github.com'"));
+        assert!(script.contains("echo \"lookup github.com $r $(elapsed \"$s\")\""));
+        assert!(!script.contains("bad name"));
+        assert!(script.contains("exec 3<>/dev/tcp/10.0.0.1/53"));
+        assert!(script.contains("exec 3<>/dev/tcp/fd00::1/53"));
+        assert_eq!(script.matches("echo \"lookup").count(), 1);
+        assert_eq!(script.matches("echo \"server").count(), 2);
+    }
+
+    #[test]
+    fn parses_the_output() {
+        let report = parse_output(
+            "lookup github.com 0 12\n\
+             lookup example.com 2 -\n\
+             server 10.0.0.1 0 3\n\
+             server fd00::1 124 3000\n\
+             server not-an-address 0 1\n\
+             unexpected output\n",
+        );
+        assert_eq!(report.lookups.len(), 2);
+        assert!(report.lookups[0].success);
+        assert_eq!(report.lookups[0].latency_ms, Some(12));
+        assert!(!report.lookups[1].success);
+        assert_eq!(report.lookups[1].latency_ms, None);
+        assert_eq!(report.servers.len(), 2);
+        assert!(report.servers[0].reachable);
+        assert!(!report.servers[1].reachable);
+        assert_eq!(
+            report.summary(),
+            "lookup of example.com failed, fd00::1 unreachable"
+        );
+    }
+
+    #[test]
+    fn is_unhealthy_if_a_lookup_fails_or_a_server_is_unreachable() {
+        assert!(parse_output("").is_healthy());
+        assert!(parse_output("lookup github.com 0 1\nserver 10.0.0.1 0 1\n").is_healthy());
+        assert!(!parse_output("lookup github.com 1 1\nserver 10.0.0.1 0 1\n").is_healthy());
+        assert!(!parse_output("lookup github.com 0 1\nserver 10.0.0.1 1 1\n").is_healthy());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::config::LogSetting;
+use crate::APP_NAME;
+use log::{LevelFilter, Log, Metadata, Record};
+use simplelog::{SharedLogger, WriteLogger};
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const LOG_NAME: &str = "log";
+const LOG_EXTENSION: &str = "txt";
+
+/// Location of the logs in "AppData\Local\WSL2 DNS Agent"
+pub fn directory() -> PathBuf {
+    let local_appdata = dirs::data_local_dir().unwrap().join(APP_NAME);
+    fs::create_dir_all(&local_appdata).unwrap();
+    local_appdata
+}
+
+/// Path of the current log, or of a previous (rotated) log
+fn log_path(directory: &Path, index: usize) -> PathBuf {
+    match index {
+        0 => directory.join(format!("{LOG_NAME}.{LOG_EXTENSION}")),
+        i => directory.join(format!("{LOG_NAME}.{i}.{LOG_EXTENSION}")),
+    }
+}
+
+/// Sets up logging to a rotating file within the directory, returning the path of the current log
+pub fn init(setting: &LogSetting, directory: &Path) -> PathBuf {
+    let writer = RotatingFile::open(setting, directory);
+    let path = writer.path();
+    let inner = WriteLogger::new(LevelFilter::Trace, simplelog::Config::default(), writer);
+    let logger = FilteredLogger::new(setting, inner);
+    log::set_max_level(logger.max_level());
+    log::set_boxed_logger(Box::new(logger)).unwrap();
+    path
+}
+
+/// The index of a previous (rotated) log from its file name
+fn log_index(name: &str) -> Option<usize> {
+    let index = name
+        .strip_prefix(&format!("{LOG_NAME}."))?
+        .strip_suffix(&format!(".{LOG_EXTENSION}"))?;
+    index.parse().ok().filter(|index| *index > 0)
+}
+
+/// A log file that is moved aside when it grows too large, keeping a limited number of
+/// previous files. Writes are held until the logger flushes at the end of each record, so
+/// that a record is never split across files
+struct RotatingFile {
+    directory: PathBuf,
+    file: Option<File>,
+    record: Vec<u8>,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+    max_age: Duration,
+}
+
+impl RotatingFile {
+    fn open(setting: &LogSetting, directory: &Path) -> Self {
+        let mut rotating = Self {
+            directory: directory.to_path_buf(),
+            file: None,
+            record: Vec::new(),
+            size: 0,
+            max_size: setting.max_size_mb * 1024 * 1024,
+            max_files: setting.max_files,
+            max_age: Duration::from_secs(setting.max_age_days * 24 * 60 * 60),
+        };
+        // Always start a new log file, preserving the log from the previous session
+        rotating.rotate();
+        rotating
+    }
+
+    fn path(&self) -> PathBuf {
+        log_path(&self.directory, 0)
+    }
+
+    fn rotate(&mut self) {
+        // The current file must be closed before it can be renamed
+        self.file = None;
+        // Shift each previous log along by one, the oldest will be overwritten
+        for index in (0..self.max_files).rev() {
+            let from = log_path(&self.directory, index);
+            if from.exists() {
+                fs::rename(&from, log_path(&self.directory, index + 1)).ok();
+            }
+        }
+        // Remove logs beyond the retention limits, including any left after a gap in the indexes
+        let now = SystemTime::now();
+        for entry in fs::read_dir(&self.directory)
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            let index = match entry.file_name().to_str().and_then(log_index) {
+                Some(index) => index,
+                None => continue,
+            };
+            let age = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .map(|modified| now.duration_since(modified).unwrap_or_default())
+                .unwrap_or_default();
+            if index > self.max_files || age > self.max_age {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+        self.file = File::create(self.path()).ok();
+        self.size = 0;
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let record = std::mem::take(&mut self.record);
+        let length = record.len() as u64;
+        if self.max_size > 0 && self.size > 0 && self.size + length > self.max_size {
+            self.rotate();
+        }
+        let file = self.file.as_mut().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Unable to create log file")
+        })?;
+        file.write_all(&record)?;
+        self.size += length;
+        file.flush()
+    }
+}
+
+/// Applies a log level per module, before passing records to the underlying logger
+struct FilteredLogger {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    inner: Box<dyn SharedLogger>,
+}
+
+impl FilteredLogger {
+    fn new(setting: &LogSetting, inner: Box<dyn SharedLogger>) -> Self {
+        let modules = setting
+            .modules
+            .iter()
+            .map(|(module, level)| {
+                (
+                    format!("{}::{}", env!("CARGO_CRATE_NAME"), module),
+                    (*level).into(),
+                )
+            })
+            .collect();
+        Self {
+            default: setting.level.into(),
+            modules,
+            inner,
+        }
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| target == module || target.starts_with(&format!("{module}::")))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+}
+
+impl Log for FilteredLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+            // Marks the end of the record for the rotating file
+            self.inner.flush();
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("{}-{name}", env!("CARGO_CRATE_NAME")));
+        fs::remove_dir_all(&directory).ok();
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn setting(max_files: usize) -> LogSetting {
+        let mut setting: LogSetting = toml::from_str("").unwrap();
+        setting.max_files = max_files;
+        setting
+    }
+
+    fn write_record(file: &mut RotatingFile, parts: &[&str]) {
+        for part in parts {
+            file.write_all(part.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+    }
+
+    #[test]
+    fn rotates_between_records() {
+        let directory = directory("rotates_between_records");
+        let mut file = RotatingFile::open(&setting(3), &directory);
+        file.max_size = 20;
+        write_record(&mut file, &["first ", "record\n"]);
+        write_record(&mut file, &["second ", "record\n"]);
+        write_record(&mut file, &["a record longer than the maximum\n"]);
+        let read = |index| fs::read_to_string(log_path(&directory, index)).unwrap();
+        assert_eq!(read(2), "first record\n");
+        assert_eq!(read(1), "second record\n");
+        assert_eq!(read(0), "a record longer than the maximum\n");
+    }
+
+    #[test]
+    fn removes_logs_beyond_the_limit() {
+        let directory = directory("removes_logs_beyond_the_limit");
+        for index in [0, 1, 4, 7] {
+            fs::write(log_path(&directory, index), "").unwrap();
+        }
+        fs::write(directory.join("other.txt"), "").unwrap();
+        RotatingFile::open(&setting(3), &directory);
+        let mut names = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["log.1.txt", "log.2.txt", "log.txt", "other.txt"]);
+    }
+
+    #[test]
+    fn finds_the_index_of_previous_logs() {
+        assert_eq!(log_index("log.2.txt"), Some(2));
+        assert_eq!(log_index("log.txt"), None);
+        assert_eq!(log_index("log.0.txt"), None);
+        assert_eq!(log_index("log.old.txt"), None);
+        assert_eq!(log_index("other.1.txt"), None);
+    }
+}
@@ -1,92 +1,115 @@
 #![windows_subsystem = "windows"]
-use crate::runner::{start_runner, RunReason};
+use crate::request::RunReason;
+use crate::runner::start_runner;
 use crate::tray::Tray;
-use log::LevelFilter;
-use simplelog::WriteLogger;
-use std::ffi::c_void;
-use std::fs;
-use std::fs::File;
-use std::sync::mpsc::Sender;
+use std::backtrace::Backtrace;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
 use win32_utils::instance::UniqueInstance;
 use win32_utils::str::ToWin32Str;
 use windows::core::PCWSTR;
-use windows::Win32::Foundation::{BOOLEAN, HANDLE, HWND};
-use windows::Win32::NetworkManagement::IpHelper::{
-    NotifyRouteChange2, MIB_IPFORWARD_ROW2, MIB_NOTIFICATION_TYPE,
-};
-use windows::Win32::Networking::WinSock::AF_UNSPEC;
+use windows::Win32::Foundation::HWND;
 use windows::Win32::UI::WindowsAndMessaging::{MessageBoxW, MB_ICONSTOP, MB_OK};
 
+mod bundle;
+mod cli;
 mod config;
+mod debounce;
+mod diagnostics;
 mod dns;
+mod event_log;
+mod events;
+mod health;
+mod logging;
+mod metrics;
+mod probe;
+mod request;
+mod routing;
 mod runner;
 mod tray;
+mod watcher;
+mod wire;
 mod wsl;
+mod wslconfig;
 
 pub const APP_NAME: &str = "WSL2 DNS Agent";
 
 fn main() {
     set_panic();
 
+    // Run a command line action instead, if one is specified
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+
     let _unique = match UniqueInstance::acquire_unique_to_session(APP_NAME) {
         Ok(u) => u,
         Err(win32_utils::instance::Error::AlreadyExists) => panic!("Application already running"),
         Err(e) => panic!("{}", e),
     };
 
+    // Load config file, but don't fail until logging has been setup
+    let config = config::Config::try_load();
+
     // Setup logging to "AppData\Local\WSL2 DNS Agent\log.txt"
-    let local_appdata = dirs::data_local_dir().unwrap().join(APP_NAME);
-    fs::create_dir_all(&local_appdata).unwrap();
-    let log_path = local_appdata.join("log.txt");
-    let log_file = File::create(&log_path).unwrap();
-    WriteLogger::init(LevelFilter::Info, simplelog::Config::default(), log_file).unwrap();
+    let local_appdata = logging::directory();
+    let log_setting = match &config {
+        Ok(config) => config.logging.clone(),
+        Err(_) => Default::default(),
+    };
+    let log_path = logging::init(&log_setting, &local_appdata);
 
     log::info!("{} version: {}", APP_NAME, env!("CARGO_PKG_VERSION"));
 
-    // Load config file
-    let config = config::Config::load();
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => panic!("{}", e),
+    };
     log::info!("Loaded config: {:?}", config);
+    for warning in config.warnings() {
+        log::warn!("{warning}");
+    }
 
-    // Listen to route table notifications
+    // Listen to route table, interface and address notifications
     let (tx, rx) = runner::channel();
-    let tx_notify = Box::new(tx.clone());
-    let mut handle = HANDLE::default();
-    unsafe {
-        NotifyRouteChange2(
-            AF_UNSPEC.0 as u16,
-            Some(callback),
-            (tx_notify.as_ref() as *const Sender<RunReason>) as *const c_void,
-            BOOLEAN(0),
-            &mut handle,
-        )
-        .unwrap();
-    }
+    let _sources = events::start_sources(&config.events, &tx);
 
     // Create tray
-    let tray = Tray::new(log_path, tx.clone());
+    let paused = Arc::new(AtomicBool::new(false));
+    let known = Arc::new(Mutex::new(Vec::new()));
+    let tray = Tray::new(
+        log_path,
+        config::Config::path(),
+        tx.clone(),
+        paused.clone(),
+        known.clone(),
+    );
+
+    // Apply DNS to distributions as they are added or started
+    watcher::start_watcher(
+        config.watcher.clone(),
+        config.wsl_limits(),
+        tx.clone(),
+        known.clone(),
+    );
 
     // Apply DNS changes on notifications
-    start_runner(config, rx, tray.get_handle());
+    let event_log = event_log::EventLog::new(&local_appdata);
+    start_runner(config, rx, tray.get_handle(), paused, known, event_log);
     // Run automatically on startup
-    tx.send(RunReason::Startup).ok();
+    tx.send(RunReason::Startup.into()).ok();
 
     // Run Windows tray icon
     tray.run();
 }
 
-unsafe extern "system" fn callback(
-    callercontext: *const c_void,
-    _: *const MIB_IPFORWARD_ROW2,
-    _: MIB_NOTIFICATION_TYPE,
-) {
-    let tx = &*(callercontext as *const Sender<RunReason>);
-    tx.send(RunReason::RouteChange).ok();
-}
-
 fn set_panic() {
     let before = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |info| unsafe {
         before(info);
+        log::error!("{}\n{}", info, Backtrace::force_capture());
+        log::logger().flush();
         let title = "Fatal Error".to_wchar();
         let text = format!("{}", info).to_wchar();
         MessageBoxW(
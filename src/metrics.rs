@@ -0,0 +1,223 @@
+use crate::config::{MetricRule, MetricSetting};
+use crate::dns;
+use crate::dns::Adapter;
+use serde::Serialize;
+use thiserror::Error;
+use windows::Win32::Foundation::{BOOLEAN, ERROR_ACCESS_DENIED};
+use windows::Win32::NetworkManagement::IpHelper::{
+    GetIpInterfaceEntry, InitializeIpInterfaceEntry, SetIpInterfaceEntry, MIB_IPINTERFACE_ROW,
+};
+use windows::Win32::Networking::WinSock::{AF_INET, AF_INET6};
+use windows::Win32::UI::Shell::IsUserAnAdmin;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Changing interface metrics requires running the agent as administrator")]
+    NotElevated,
+    #[error("Win32 DNS error: {0}")]
+    Dns(
+        #[source]
+        #[from]
+        dns::Error,
+    ),
+    #[error("Call to GetIpInterfaceEntry() failed for {0}: {1}")]
+    GetIpInterfaceEntry(String, #[source] windows::core::Error),
+    #[error("Call to SetIpInterfaceEntry() failed for {0}: {1}")]
+    SetIpInterfaceEntry(String, #[source] windows::core::Error),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+/// An interface metric that differs from the configured value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MetricChange {
+    pub adapter: String,
+    pub interface_index: u32,
+    pub family: IpFamily,
+    pub current: u32,
+    pub desired: u32,
+}
+
+impl MetricChange {
+    fn describe(&self) -> String {
+        format!("{} ({:?})", self.adapter, self.family)
+    }
+}
+
+/// The interface index and metric of each IP family enabled on a connected adapter
+#[derive(Debug, Clone)]
+struct AdapterMetrics {
+    adapter: String,
+    ipv4: Option<(u32, u32)>,
+    ipv6: Option<(u32, u32)>,
+}
+
+impl AdapterMetrics {
+    fn new(adapter: &Adapter) -> Self {
+        Self {
+            adapter: adapter.friendly_name().to_string(),
+            ipv4: adapter.ipv4_interface(),
+            ipv6: adapter.ipv6_interface(),
+        }
+    }
+}
+
+/// Works out which of the adapter's interface metrics need changing to match the rule
+fn plan(adapter: &AdapterMetrics, rule: &MetricRule) -> Vec<MetricChange> {
+    let families = [
+        (IpFamily::Ipv4, rule.ipv4, adapter.ipv4),
+        (IpFamily::Ipv6, rule.ipv6, adapter.ipv6),
+    ];
+    families
+        .into_iter()
+        .filter_map(|(family, desired, interface)| {
+            let (desired, (interface_index, current)) = (desired?, interface?);
+            (current != desired).then(|| MetricChange {
+                adapter: adapter.adapter.clone(),
+                interface_index,
+                family,
+                current,
+                desired,
+            })
+        })
+        .collect()
+}
+
+/// Whether the agent is running as an elevated administrator
+pub fn is_elevated() -> bool {
+    unsafe { IsUserAnAdmin().as_bool() }
+}
+
+/// Applies the metric rules, returning the metrics that were changed. The first rule that
+/// matches a connected adapter applies to it
+pub fn enforce(setting: &MetricSetting) -> Result<Vec<MetricChange>, Error> {
+    let changes = dns::get_adapters()?
+        .iter()
+        .filter(|a| a.is_up())
+        .filter_map(|adapter| {
+            let rule = setting.rules.iter().find(|r| adapter.matches(&r.adapter))?;
+            Some(plan(&AdapterMetrics::new(adapter), rule))
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        return Ok(changes);
+    }
+    if !is_elevated() {
+        return Err(Error::NotElevated);
+    }
+    for change in &changes {
+        log::info!(
+            "Changing interface metric of {} from {} to {}",
+            change.describe(),
+            change.current,
+            change.desired
+        );
+        apply(change)?;
+    }
+    Ok(changes)
+}
+
+/// Sets a fixed interface metric, which also disables the automatic metric
+fn apply(change: &MetricChange) -> Result<(), Error> {
+    let access_denied = |e: &windows::core::Error| e.win32_error() == Some(ERROR_ACCESS_DENIED);
+    unsafe {
+        let mut row = MIB_IPINTERFACE_ROW::default();
+        InitializeIpInterfaceEntry(&mut row);
+        row.Family = match change.family {
+            IpFamily::Ipv4 => AF_INET.0 as u16,
+            IpFamily::Ipv6 => AF_INET6.0 as u16,
+        };
+        row.InterfaceIndex = change.interface_index;
+        GetIpInterfaceEntry(&mut row)
+            .map_err(|e| Error::GetIpInterfaceEntry(change.describe(), e))?;
+        row.Metric = change.desired;
+        row.UseAutomaticMetric = BOOLEAN(0);
+        // SetIpInterfaceEntry() rejects the value returned by GetIpInterfaceEntry() for IPv4
+        if change.family == IpFamily::Ipv4 {
+            row.SitePrefixLength = 0;
+        }
+        SetIpInterfaceEntry(&mut row).map_err(|e| {
+            if access_denied(&e) {
+                Error::NotElevated
+            } else {
+                Error::SetIpInterfaceEntry(change.describe(), e)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AdapterRule;
+
+    fn adapter(ipv4: Option<(u32, u32)>, ipv6: Option<(u32, u32)>) -> AdapterMetrics {
+        AdapterMetrics {
+            adapter: "Ethernet".to_string(),
+            ipv4,
+            ipv6,
+        }
+    }
+
+    fn rule(ipv4: Option<u32>, ipv6: Option<u32>) -> MetricRule {
+        MetricRule {
+            adapter: toml::from_str::<AdapterRule>("name = \"Ethernet\"").unwrap(),
+            ipv4,
+            ipv6,
+        }
+    }
+
+    fn change(family: IpFamily, interface_index: u32, current: u32, desired: u32) -> MetricChange {
+        MetricChange {
+            adapter: "Ethernet".to_string(),
+            interface_index,
+            family,
+            current,
+            desired,
+        }
+    }
+
+    #[test]
+    fn changes_each_family_that_differs() {
+        let changes = plan(
+            &adapter(Some((4, 25)), Some((6, 25))),
+            &rule(Some(5), Some(10)),
+        );
+        assert_eq!(
+            changes,
+            vec![
+                change(IpFamily::Ipv4, 4, 25, 5),
+                change(IpFamily::Ipv6, 6, 25, 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_metrics_that_already_match() {
+        let changes = plan(
+            &adapter(Some((4, 5)), Some((6, 25))),
+            &rule(Some(5), Some(10)),
+        );
+        assert_eq!(changes, vec![change(IpFamily::Ipv6, 6, 25, 10)]);
+    }
+
+    #[test]
+    fn leaves_families_without_a_metric_in_the_rule() {
+        let changes = plan(&adapter(Some((4, 25)), Some((6, 25))), &rule(Some(5), None));
+        assert_eq!(changes, vec![change(IpFamily::Ipv4, 4, 25, 5)]);
+        assert!(plan(&adapter(Some((4, 25)), Some((6, 25))), &rule(None, None)).is_empty());
+    }
+
+    #[test]
+    fn ignores_families_that_are_disabled() {
+        let changes = plan(&adapter(None, Some((6, 25))), &rule(Some(5), Some(10)));
+        assert_eq!(changes, vec![change(IpFamily::Ipv6, 6, 25, 10)]);
+        assert!(plan(&adapter(None, None), &rule(Some(5), Some(10))).is_empty());
+    }
+}
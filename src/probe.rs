@@ -0,0 +1,317 @@
+use crate::config::ProbeSetting;
+use crate::wire::{Message, RecordType, EDNS_PAYLOAD_SIZE};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const DNS_PORT: u16 = 53;
+
+#[derive(Debug, Copy, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// Whether a DNS server responded to a query, and how long it took
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeResult {
+    pub server: IpAddr,
+    pub reachable: bool,
+    pub transport: Option<Transport>,
+    pub latency_ms: u64,
+}
+
+/// Recent probe results, so that bursts of runs don't repeatedly wait for unreachable servers
+#[derive(Default)]
+pub struct ProbeCache {
+    results: HashMap<IpAddr, (Instant, ProbeResult)>,
+}
+
+impl ProbeCache {
+    /// Probes the port of each server (in parallel) unless there is a recent cached result
+    pub fn probe_all(
+        &mut self,
+        servers: &[IpAddr],
+        port: u16,
+        setting: &ProbeSetting,
+    ) -> Vec<ProbeResult> {
+        let max_age = Duration::from_secs(setting.cache_secs);
+        self.results.retain(|_, (time, _)| time.elapsed() < max_age);
+        let uncached = servers
+            .iter()
+            .filter(|s| !self.results.contains_key(s))
+            .collect::<Vec<_>>();
+        let probed = std::thread::scope(|scope| {
+            uncached
+                .iter()
+                .map(|server| {
+                    let address = SocketAddr::new(**server, port);
+                    scope.spawn(move || probe(address, setting))
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        for result in probed {
+            log::info!("Probed DNS server: {result:?}");
+            self.results.insert(result.server, (Instant::now(), result));
+        }
+        servers
+            .iter()
+            .filter_map(|s| self.results.get(s))
+            .map(|(_, result)| result.clone())
+            .collect()
+    }
+}
+
+/// Sends a query to the server over UDP, falling back to TCP if there is no response or the
+/// response is truncated. A truncated response still shows the server is reachable over UDP
+pub fn probe(server: SocketAddr, setting: &ProbeSetting) -> ProbeResult {
+    let timeout = Duration::from_millis(setting.timeout_ms);
+    let start = Instant::now();
+    let transport = match query_udp(server, &setting.name, timeout) {
+        Ok(false) => Some(Transport::Udp),
+        Ok(true) => Some(match query_tcp(server, &setting.name, timeout) {
+            Ok(_) => Transport::Tcp,
+            Err(_) => Transport::Udp,
+        }),
+        Err(_) => query_tcp(server, &setting.name, timeout)
+            .ok()
+            .map(|_| Transport::Tcp),
+    };
+    ProbeResult {
+        server: server.ip(),
+        reachable: transport.is_some(),
+        transport,
+        latency_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+fn build_query(name: &str) -> std::io::Result<(Message, Vec<u8>)> {
+    let query = Message::query(query_id(), name, RecordType::A);
+    let encoded = query
+        .encode()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    Ok((query, encoded))
+}
+
+/// Any response to the query counts, even an error, since the server is reachable
+fn decode_response(query: &Message, response: &[u8]) -> Option<Message> {
+    Message::decode(response)
+        .ok()
+        .filter(|r| r.is_response_to(query))
+}
+
+fn query_id() -> u16 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u16
+}
+
+/// Returns whether the response was truncated
+fn query_udp(server: SocketAddr, name: &str, timeout: Duration) -> std::io::Result<bool> {
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(server)?;
+    let (query, encoded) = build_query(name)?;
+    socket.send(&encoded)?;
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0u8; EDNS_PAYLOAD_SIZE as usize];
+    // Ignore any stray datagrams that aren't a response to this query, waiting only for the time
+    // remaining overall rather than the full timeout for each
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let length = socket.recv(&mut buffer)?;
+        if let Some(response) = decode_response(&query, &buffer[..length]) {
+            return Ok(response.header.truncated);
+        }
+    }
+}
+
+fn query_tcp(server: SocketAddr, name: &str, timeout: Duration) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let (query, encoded) = build_query(name)?;
+    // Messages over TCP are prefixed with a two byte length
+    let mut message = (encoded.len() as u16).to_be_bytes().to_vec();
+    message.extend(encoded);
+    stream.write_all(&message)?;
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length)?;
+    let mut response = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut response)?;
+    if decode_response(&query, &response).is_some() {
+        Ok(())
+    } else {
+        Err(std::io::ErrorKind::InvalidData.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{Record, RecordData, CLASS_IN};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    /// How the stub server responds to queries over UDP
+    #[derive(Copy, Clone)]
+    enum Reply {
+        Answer,
+        Truncated,
+        Drop,
+    }
+
+    /// A DNS server on localhost, counting the queries it receives
+    struct Stub {
+        address: SocketAddr,
+        udp_queries: Arc<AtomicUsize>,
+        tcp_queries: Arc<AtomicUsize>,
+    }
+
+    fn respond(query: &[u8], truncated: bool) -> Vec<u8> {
+        let mut response = Message::decode(query).unwrap();
+        response.header.response = true;
+        response.header.truncated = truncated;
+        if !truncated {
+            response.answers.push(Record {
+                name: response.questions[0].name.clone(),
+                rtype: RecordType::A,
+                class: CLASS_IN,
+                ttl: 60,
+                data: RecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            });
+        }
+        response.encode().unwrap()
+    }
+
+    fn stub(reply: Reply, tcp: bool) -> Stub {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = udp.local_addr().unwrap();
+        let udp_queries = Arc::new(AtomicUsize::new(0));
+        let tcp_queries = Arc::new(AtomicUsize::new(0));
+        let counter = udp_queries.clone();
+        spawn(move || {
+            let mut buffer = [0u8; 512];
+            while let Ok((length, from)) = udp.recv_from(&mut buffer) {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let response = match reply {
+                    Reply::Answer => respond(&buffer[..length], false),
+                    Reply::Truncated => respond(&buffer[..length], true),
+                    Reply::Drop => continue,
+                };
+                udp.send_to(&response, from).unwrap();
+            }
+        });
+        if tcp {
+            let listener = TcpListener::bind(address).unwrap();
+            let counter = tcp_queries.clone();
+            spawn(move || {
+                for mut stream in listener.incoming().flatten() {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let mut length = [0u8; 2];
+                    stream.read_exact(&mut length).unwrap();
+                    let mut query = vec![0u8; u16::from_be_bytes(length) as usize];
+                    stream.read_exact(&mut query).unwrap();
+                    let response = respond(&query, false);
+                    let mut message = (response.len() as u16).to_be_bytes().to_vec();
+                    message.extend(response);
+                    stream.write_all(&message).unwrap();
+                }
+            });
+        }
+        Stub {
+            address,
+            udp_queries,
+            tcp_queries,
+        }
+    }
+
+    fn setting() -> ProbeSetting {
+        ProbeSetting {
+            timeout_ms: 200,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reachable_over_udp() {
+        let stub = stub(Reply::Answer, true);
+        let result = probe(stub.address, &setting());
+        assert!(result.reachable);
+        assert_eq!(result.transport, Some(Transport::Udp));
+        assert_eq!(stub.tcp_queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn falls_back_to_tcp_without_a_response() {
+        let stub = stub(Reply::Drop, true);
+        let result = probe(stub.address, &setting());
+        assert!(result.reachable);
+        assert_eq!(result.transport, Some(Transport::Tcp));
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn falls_back_to_tcp_when_truncated() {
+        let stub = stub(Reply::Truncated, true);
+        let result = probe(stub.address, &setting());
+        assert_eq!(result.transport, Some(Transport::Tcp));
+        assert_eq!(stub.tcp_queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn truncated_without_tcp_is_reachable_over_udp() {
+        let stub = stub(Reply::Truncated, false);
+        let result = probe(stub.address, &setting());
+        assert_eq!(result.transport, Some(Transport::Udp));
+    }
+
+    #[test]
+    fn unreachable_without_any_response() {
+        let stub = stub(Reply::Drop, false);
+        let result = probe(stub.address, &setting());
+        assert!(!result.reachable);
+        assert_eq!(result.transport, None);
+    }
+
+    #[test]
+    fn caches_results() {
+        let stub = stub(Reply::Answer, false);
+        let mut cache = ProbeCache::default();
+        let setting = ProbeSetting {
+            cache_secs: 60,
+            ..setting()
+        };
+        let servers = [stub.address.ip()];
+        for _ in 0..2 {
+            let results = cache.probe_all(&servers, stub.address.port(), &setting);
+            assert_eq!(results.len(), 1);
+            assert!(results[0].reachable);
+        }
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 1);
+
+        // Results older than the cache duration are probed again
+        let setting = ProbeSetting {
+            cache_secs: 0,
+            ..setting
+        };
+        cache.probe_all(&servers, stub.address.port(), &setting);
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 2);
+    }
+}
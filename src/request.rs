@@ -0,0 +1,230 @@
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum RunReason {
+    Startup,
+    RouteChange,
+    InterfaceChange,
+    AddressChange,
+    /// The network changed since it was last polled, without any notification
+    NetworkPoll,
+    TrayButton,
+    ConfigReload,
+    DistributionAdded(String),
+    DistributionStarted(String),
+    /// Retrying after a transient failure, with the number of previous retries
+    Retry(u32),
+    /// Running again shortly after a route change, in case DNS servers were assigned late
+    FollowUp,
+}
+
+impl RunReason {
+    pub fn retries(&self) -> u32 {
+        match self {
+            RunReason::Retry(retries) => *retries,
+            _ => 0,
+        }
+    }
+
+    pub fn is_network_change(&self) -> bool {
+        matches!(
+            self,
+            RunReason::RouteChange
+                | RunReason::InterfaceChange
+                | RunReason::AddressChange
+                | RunReason::NetworkPoll
+        )
+    }
+
+    /// Runs for these reasons can be skipped if nothing relevant to DNS has changed
+    pub fn is_network_driven(&self) -> bool {
+        self.is_network_change() || matches!(self, RunReason::FollowUp)
+    }
+
+    pub fn is_lifecycle_event(&self) -> bool {
+        matches!(
+            self,
+            RunReason::DistributionAdded(_) | RunReason::DistributionStarted(_)
+        )
+    }
+}
+
+/// The distributions that a run should update
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Targets {
+    All,
+    Only(BTreeSet<String>),
+}
+
+impl Targets {
+    pub fn only(name: &str) -> Self {
+        Targets::Only(BTreeSet::from([name.to_string()]))
+    }
+
+    /// Combines the targets of two requests, a request for all distributions absorbs any other
+    pub fn union(self, other: Targets) -> Targets {
+        match (self, other) {
+            (Targets::Only(mut a), Targets::Only(b)) => {
+                a.extend(b);
+                Targets::Only(a)
+            }
+            _ => Targets::All,
+        }
+    }
+
+    pub fn includes(&self, name: &str) -> bool {
+        match self {
+            Targets::All => true,
+            Targets::Only(names) => names.contains(name),
+        }
+    }
+}
+
+/// A request to run, and which distributions to update
+#[derive(Debug, Clone)]
+pub struct RunRequest {
+    pub reason: RunReason,
+    pub targets: Targets,
+}
+
+impl RunRequest {
+    pub fn new(reason: RunReason, targets: Targets) -> Self {
+        Self { reason, targets }
+    }
+}
+
+/// Lifecycle events only update their own distribution, anything else updates all of them
+impl From<RunReason> for RunRequest {
+    fn from(reason: RunReason) -> Self {
+        let targets = match &reason {
+            RunReason::DistributionAdded(name) | RunReason::DistributionStarted(name) => {
+                Targets::only(name)
+            }
+            _ => Targets::All,
+        };
+        Self::new(reason, targets)
+    }
+}
+
+/// Requests received while debouncing, merged into a single run
+#[derive(Debug)]
+pub struct Batch {
+    /// The reason for the first request
+    pub reason: RunReason,
+    pub targets: Targets,
+    /// Number of further requests merged into the first
+    pub debounced: usize,
+    pub reload: bool,
+    pub lifecycle_only: bool,
+    /// Distributions that were added, which should be updated even though they aren't running
+    pub added: BTreeSet<String>,
+    pub network_change: bool,
+    /// Every request was due to the network, so the run can be skipped if nothing changed
+    pub network_only: bool,
+    /// The most retries of any request
+    pub retries: u32,
+}
+
+impl Batch {
+    pub fn new(request: RunRequest) -> Self {
+        Self {
+            reload: matches!(request.reason, RunReason::ConfigReload),
+            lifecycle_only: request.reason.is_lifecycle_event(),
+            added: added(&request.reason).into_iter().collect(),
+            network_change: request.reason.is_network_change(),
+            network_only: request.reason.is_network_driven(),
+            retries: request.reason.retries(),
+            reason: request.reason,
+            targets: request.targets,
+            debounced: 0,
+        }
+    }
+
+    pub fn merge(&mut self, request: RunRequest) {
+        self.debounced += 1;
+        self.reload |= matches!(request.reason, RunReason::ConfigReload);
+        self.lifecycle_only &= request.reason.is_lifecycle_event();
+        self.added.extend(added(&request.reason));
+        self.network_change |= request.reason.is_network_change();
+        self.network_only &= request.reason.is_network_driven();
+        self.retries = self.retries.max(request.reason.retries());
+        let targets = std::mem::replace(&mut self.targets, Targets::All);
+        self.targets = targets.union(request.targets);
+    }
+}
+
+fn added(reason: &RunReason) -> Option<String> {
+    match reason {
+        RunReason::DistributionAdded(name) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(reasons: Vec<RunReason>) -> Batch {
+        let mut reasons = reasons.into_iter().map(RunRequest::from);
+        let mut batch = Batch::new(reasons.next().unwrap());
+        reasons.for_each(|request| batch.merge(request));
+        batch
+    }
+
+    #[test]
+    fn merges_network_changes() {
+        let batch = merge(vec![
+            RunReason::RouteChange,
+            RunReason::InterfaceChange,
+            RunReason::AddressChange,
+        ]);
+        assert!(matches!(batch.reason, RunReason::RouteChange));
+        assert_eq!(batch.debounced, 2);
+        assert_eq!(batch.targets, Targets::All);
+        assert!(batch.network_change);
+        assert!(batch.network_only);
+        assert!(!batch.lifecycle_only);
+        assert!(!batch.reload);
+    }
+
+    #[test]
+    fn merges_lifecycle_events() {
+        let batch = merge(vec![
+            RunReason::DistributionAdded("Ubuntu".to_string()),
+            RunReason::DistributionStarted("Debian".to_string()),
+        ]);
+        assert!(batch.lifecycle_only);
+        assert!(!batch.network_change);
+        assert_eq!(batch.added, BTreeSet::from(["Ubuntu".to_string()]));
+        assert_eq!(
+            batch.targets,
+            Targets::Only(BTreeSet::from(["Debian".to_string(), "Ubuntu".to_string()]))
+        );
+    }
+
+    #[test]
+    fn merges_other_reasons() {
+        let batch = merge(vec![
+            RunReason::Retry(2),
+            RunReason::DistributionStarted("Ubuntu".to_string()),
+            RunReason::ConfigReload,
+            RunReason::Retry(1),
+        ]);
+        assert_eq!(batch.debounced, 3);
+        assert_eq!(batch.retries, 2);
+        assert_eq!(batch.targets, Targets::All);
+        assert!(batch.reload);
+        assert!(!batch.lifecycle_only);
+        assert!(!batch.network_only);
+    }
+
+    #[test]
+    fn follow_ups_are_network_driven_but_not_changes() {
+        let batch = merge(vec![RunReason::FollowUp, RunReason::NetworkPoll]);
+        assert!(batch.network_only);
+        assert!(batch.network_change);
+        assert!(!merge(vec![RunReason::FollowUp]).network_change);
+    }
+}
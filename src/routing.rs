@@ -0,0 +1,282 @@
+use crate::dns;
+use crate::dns::{Adapter, Route};
+use crate::metrics;
+use serde::Serialize;
+use std::net::IpAddr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Changing routes requires running the agent as administrator")]
+    NotElevated,
+    #[error("Win32 DNS error: {0}")]
+    Dns(
+        #[source]
+        #[from]
+        dns::Error,
+    ),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    /// Covers part of the WSL subnet with a longer prefix, so always takes precedence
+    MoreSpecific,
+    /// Covers the same prefix as the WSL subnet, with a lower cost
+    LowerCost,
+}
+
+/// A route that sends traffic for the WSL subnet to a different interface, typically added by a
+/// VPN that isn't split tunnel
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteConflict {
+    pub kind: ConflictKind,
+    pub route: Route,
+    /// Name of the adapter that the route uses
+    pub adapter: String,
+    /// Route metric plus interface metric
+    pub cost: u32,
+    pub wsl_subnet: Route,
+    pub wsl_cost: u32,
+}
+
+impl RouteConflict {
+    pub fn describe(&self) -> String {
+        let route = format!(
+            "{}/{} via {} ({})",
+            self.route.destination_prefix_ip,
+            self.route.destination_prefix_len,
+            self.route.next_hop,
+            self.adapter
+        );
+        let subnet = format!(
+            "{}/{}",
+            self.wsl_subnet.destination_prefix_ip, self.wsl_subnet.destination_prefix_len
+        );
+        match self.kind {
+            ConflictKind::MoreSpecific => {
+                format!("{route} is more specific than the WSL subnet {subnet}")
+            }
+            ConflictKind::LowerCost => format!(
+                "{route} has a lower cost than the WSL subnet {subnet} ({} < {})",
+                self.cost, self.wsl_cost
+            ),
+        }
+    }
+
+    /// The route metric that would make the route cost more than the WSL subnet route, if
+    /// changing the metric is enough to fix it
+    fn fixed_metric(&self) -> Option<u32> {
+        let interface_metric = self.cost - self.route.metric;
+        match self.kind {
+            ConflictKind::MoreSpecific => None,
+            ConflictKind::LowerCost => Some((self.wsl_cost + 1).saturating_sub(interface_metric)),
+        }
+    }
+}
+
+/// The WSL NAT adapter, "vEthernet (WSL)" or "vEthernet (WSL (Hyper-V firewall))"
+pub fn is_wsl_adapter(adapter: &Adapter) -> bool {
+    adapter.friendly_name().starts_with("vEthernet (WSL")
+}
+
+/// The IPv4 interface of an adapter, which routes go via
+#[derive(Debug, Clone)]
+pub struct Interface {
+    pub index: u32,
+    pub name: String,
+    pub metric: u32,
+    /// Whether this is the WSL NAT adapter
+    pub is_wsl: bool,
+}
+
+/// The IPv4 interfaces of the adapters that have IPv4 enabled
+pub fn interfaces(adapters: &[Adapter]) -> Vec<Interface> {
+    adapters
+        .iter()
+        .filter_map(|adapter| {
+            let (index, metric) = adapter.ipv4_interface()?;
+            Some(Interface {
+                index,
+                name: adapter.friendly_name().to_string(),
+                metric,
+                is_wsl: is_wsl_adapter(adapter),
+            })
+        })
+        .collect()
+}
+
+/// Finds the routes that take precedence over the WSL subnet routes, based on longest prefix
+/// match and then the lowest cost
+pub fn find_conflicts(routes: &[Route], interfaces: &[Interface]) -> Vec<RouteConflict> {
+    let Some(wsl_index) = interfaces.iter().find(|i| i.is_wsl).map(|i| i.index) else {
+        return vec![];
+    };
+    let interface = |index: u32| interfaces.iter().find(|i| i.index == index);
+    let cost = |route: &Route| {
+        route
+            .metric
+            .saturating_add(interface(route.interface_index).map_or(0, |i| i.metric))
+    };
+    let is_ipv4_subnet = |route: &&Route| match route.destination_prefix_ip {
+        IpAddr::V4(ip) => !ip.is_multicast() && (1..32).contains(&route.destination_prefix_len),
+        IpAddr::V6(_) => false,
+    };
+    let subnets = routes
+        .iter()
+        .filter(is_ipv4_subnet)
+        .filter(|r| r.interface_index == wsl_index && r.next_hop.is_unspecified());
+    let mut conflicts = vec![];
+    for subnet in subnets {
+        let wsl_cost = cost(subnet);
+        for route in routes
+            .iter()
+            .filter(is_ipv4_subnet)
+            .filter(|r| r.interface_index != wsl_index && r.overlaps(subnet))
+        {
+            let kind = if route.destination_prefix_len > subnet.destination_prefix_len {
+                ConflictKind::MoreSpecific
+            } else if route.destination_prefix_len == subnet.destination_prefix_len
+                && cost(route) < wsl_cost
+            {
+                ConflictKind::LowerCost
+            } else {
+                continue;
+            };
+            let adapter = interface(route.interface_index).map_or_else(
+                || format!("interface {}", route.interface_index),
+                |i| i.name.clone(),
+            );
+            conflicts.push(RouteConflict {
+                kind,
+                route: route.clone(),
+                adapter,
+                cost: cost(route),
+                wsl_subnet: subnet.clone(),
+                wsl_cost,
+            });
+        }
+    }
+    conflicts
+}
+
+/// Checks the current route table for conflicts with the WSL subnet
+pub fn check() -> Result<Vec<RouteConflict>, Error> {
+    let conflicts = find_conflicts(&dns::get_routes()?, &interfaces(&dns::get_adapters()?));
+    for conflict in &conflicts {
+        log::warn!("Route conflicts with WSL: {}", conflict.describe());
+    }
+    Ok(conflicts)
+}
+
+/// Raises the metric of conflicting routes above the WSL subnet route, returning the number of
+/// routes that were changed
+pub fn fix(conflicts: &[RouteConflict]) -> Result<usize, Error> {
+    let fixable = conflicts
+        .iter()
+        .filter_map(|c| c.fixed_metric().map(|metric| (c, metric)))
+        .collect::<Vec<_>>();
+    for conflict in conflicts.iter().filter(|c| c.fixed_metric().is_none()) {
+        log::warn!(
+            "Unable to fix by changing metrics, the route must be removed: {}",
+            conflict.describe()
+        );
+    }
+    if fixable.is_empty() {
+        return Ok(0);
+    }
+    if !metrics::is_elevated() {
+        return Err(Error::NotElevated);
+    }
+    let mut fixed = 0;
+    for (conflict, metric) in fixable {
+        log::info!(
+            "Changing route metric from {} to {}: {}",
+            conflict.route.metric,
+            metric,
+            conflict.describe()
+        );
+        if dns::set_route_metric(&conflict.route, metric)? {
+            fixed += 1;
+        }
+    }
+    Ok(fixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::test_index::{ETHERNET, VPN, WSL};
+
+    fn interfaces() -> Vec<Interface> {
+        [
+            (WSL, "vEthernet (WSL)", 15),
+            (VPN, "VPN", 1),
+            (ETHERNET, "Ethernet", 25),
+        ]
+        .into_iter()
+        .map(|(index, name, metric)| Interface {
+            index,
+            name: name.to_string(),
+            metric,
+            is_wsl: index == WSL,
+        })
+        .collect()
+    }
+
+    /// The WSL subnet route, with a cost of 256 + 15
+    fn wsl_subnet() -> Route {
+        Route::test(WSL, "172.20.0.0/20", "0.0.0.0", 256)
+    }
+
+    #[test]
+    fn finds_lower_cost_routes_for_the_same_prefix() {
+        let vpn = Route::test(VPN, "172.20.0.0/20", "10.0.0.1", 1);
+        let conflicts = find_conflicts(&[wsl_subnet(), vpn.clone()], &interfaces());
+        assert_eq!(conflicts.len(), 1);
+        let conflict = &conflicts[0];
+        assert_eq!(conflict.kind, ConflictKind::LowerCost);
+        assert_eq!(conflict.route, vpn);
+        assert_eq!(conflict.adapter, "VPN");
+        assert_eq!((conflict.cost, conflict.wsl_cost), (2, 271));
+        assert_eq!(conflict.fixed_metric(), Some(271));
+    }
+
+    #[test]
+    fn ignores_higher_cost_routes_for_the_same_prefix() {
+        let ethernet = Route::test(ETHERNET, "172.20.0.0/20", "192.168.1.1", 500);
+        assert!(find_conflicts(&[wsl_subnet(), ethernet], &interfaces()).is_empty());
+    }
+
+    #[test]
+    fn finds_more_specific_routes() {
+        let vpn = Route::test(VPN, "172.20.1.0/24", "10.0.0.1", 1000);
+        let conflicts = find_conflicts(&[wsl_subnet(), vpn.clone()], &interfaces());
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kind, ConflictKind::MoreSpecific);
+        assert_eq!(conflicts[0].route, vpn);
+        assert_eq!(conflicts[0].fixed_metric(), None);
+    }
+
+    #[test]
+    fn ignores_less_specific_routes() {
+        let vpn = Route::test(VPN, "172.16.0.0/12", "10.0.0.1", 1);
+        assert!(find_conflicts(&[wsl_subnet(), vpn], &interfaces()).is_empty());
+    }
+
+    #[test]
+    fn ignores_routes_that_do_not_overlap() {
+        let vpn = Route::test(VPN, "172.21.0.0/24", "10.0.0.1", 1);
+        assert!(find_conflicts(&[wsl_subnet(), vpn], &interfaces()).is_empty());
+    }
+
+    #[test]
+    fn finds_nothing_without_a_wsl_adapter() {
+        let vpn = Route::test(VPN, "172.20.1.0/24", "10.0.0.1", 1);
+        let interfaces = interfaces()
+            .into_iter()
+            .filter(|i| !i.is_wsl)
+            .collect::<Vec<_>>();
+        assert!(find_conflicts(&[wsl_subnet(), vpn], &interfaces).is_empty());
+    }
+}
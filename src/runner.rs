@@ -1,51 +1,442 @@
-use crate::config::{Config, DistributionSetting};
+use crate::config::{
+    Config, DistributionSetting, HealthCheckSetting, NotificationVerbosity, RetrySetting,
+};
+use crate::debounce::debounce;
+use crate::diagnostics;
+use crate::diagnostics::{Finding, Rule, Severity};
 use crate::dns;
-use crate::tray::TrayHandle;
+use crate::dns::{DnsConfiguration, DnsSnapshot, NetworkSnapshot};
+use crate::event_log::{Calls, DistributionAction, DistributionRecord, EventLog, RunRecord};
+use crate::health;
+use crate::metrics;
+use crate::probe;
+use crate::probe::ProbeCache;
+use crate::request::{Batch, RunReason, RunRequest, Targets};
+use crate::routing;
+use crate::tray::{TrayHandle, TrayStatus};
 use crate::wsl;
 use crate::wsl::WslDistribution;
+use crate::wslconfig;
+use crate::wslconfig::{NetworkingMode, WslConfig};
 use configparser::ini::Ini;
-use std::sync::mpsc;
-use std::thread::spawn;
-use std::time::{Duration, Instant};
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
 const RESOLV_CONF: &str = "/etc/resolv.conf";
 const WSL_CONF: &str = "/etc/wsl.conf";
+/// How long to wait for aborted updates to stop, before leaving them to finish in the background
+const ABORT_GRACE: Duration = Duration::from_secs(5);
 
-#[derive(Debug)]
-pub enum RunReason {
-    Startup,
-    RouteChange,
-    TrayButton,
+/// Names of the WSL2 distributions seen by the most recent list, for the tray menu
+pub type KnownDistributions = Arc<Mutex<Vec<String>>>;
+
+pub fn channel() -> (mpsc::Sender<RunRequest>, mpsc::Receiver<RunRequest>) {
+    mpsc::channel()
 }
 
-const DEBOUNCE: Duration = Duration::from_millis(300);
+pub fn start_runner(
+    mut config: Config,
+    rx: mpsc::Receiver<RunRequest>,
+    tray: TrayHandle,
+    paused: Arc<AtomicBool>,
+    known: KnownDistributions,
+    event_log: EventLog,
+) {
+    // Forward requests to the runner thread, cancelling the run in progress if the network
+    // changes again, since it would be applying out of date DNS servers
+    let (run_tx, run_rx) = mpsc::channel::<RunRequest>();
+    let retry_tx = run_tx.clone();
+    let cancel = CancelToken::default();
+    // The network when the run in progress started, or `None` if there isn't one
+    let started = Arc::new(Mutex::new(None::<NetworkSnapshot>));
+    {
+        let cancel = cancel.clone();
+        let started = started.clone();
+        spawn(move || {
+            for request in rx {
+                // Notifications also fire for changes that don't matter, including those made by
+                // the run itself (such as enforcing metrics), so only cancel if it really changed
+                if request.reason.is_network_change() {
+                    if let Some(started) = &*started.lock().unwrap() {
+                        if matches!(NetworkSnapshot::capture(), Ok(now) if &now != started) {
+                            log::info!("Network changed, cancelling the run in progress");
+                            cancel.cancel();
+                        }
+                    }
+                }
+                if run_tx.send(request).is_err() {
+                    break;
+                }
+            }
+        });
+    }
 
-pub fn channel() -> (mpsc::Sender<RunReason>, mpsc::Receiver<RunReason>) {
-    mpsc::channel()
+    spawn(move || {
+        let mut rx = run_rx;
+        let mut reporter = StatusReporter::new(tray);
+        let mut probe_cache = ProbeCache::default();
+        // The snapshot when all distributions were last updated successfully
+        let mut last_applied: Option<DnsSnapshot> = None;
+        // The problems with .wslconfig that have been logged, which are only logged again if the
+        // file changes
+        let mut wsl_config_warnings = vec![];
+        loop {
+            let first = rx.recv().unwrap();
+            let Batch {
+                reason: msg,
+                targets,
+                debounced,
+                reload,
+                lifecycle_only,
+                added,
+                network_change,
+                network_only,
+                retries,
+            } = debounce(&mut rx, first, &config.debounce, || {
+                NetworkSnapshot::capture().ok()
+            });
+            if reload {
+                match Config::try_load() {
+                    Ok(new) => {
+                        log::info!("Reloaded config: {:?}", new);
+                        for warning in new.warnings() {
+                            log::warn!("{warning}");
+                        }
+                        let sections = config.restart_required(&new);
+                        if !sections.is_empty() {
+                            let message = format!(
+                                "Restart to apply changes to the [{}] config",
+                                sections.join("], [")
+                            );
+                            log::warn!("{message}");
+                            if new.show_notifications {
+                                reporter.tray.notify_info(&message);
+                            }
+                        }
+                        config = new;
+                    }
+                    Err(e) => {
+                        // The batch may include network changes, so still run with the old config
+                        log::error!("Failed to reload config, keeping the previous config: {e}");
+                        reporter
+                            .tray
+                            .notify_error(&format!("Failed to reload config: {e}"));
+                    }
+                }
+            }
+            if paused.load(Ordering::SeqCst) {
+                log::info!("Paused, ignoring {msg:?} message (and {debounced} debounced messages)");
+                continue;
+            }
+            if config.wslconfig.reconcile {
+                match wslconfig::reconcile(&config.wslconfig) {
+                    Ok(changes) => reporter.report_wslconfig_changes(&config, &changes),
+                    Err(e) => reporter.report_repair_error(
+                        &config,
+                        &format!("Failed to update .wslconfig: {e}"),
+                        false,
+                    ),
+                }
+            }
+            let wsl_config = WslConfig::load().unwrap_or_else(|e| {
+                log::warn!("{e}, assuming the default WSL settings");
+                WslConfig::default()
+            });
+            if wsl_config.warnings() != wsl_config_warnings {
+                for warning in wsl_config.warnings() {
+                    log::warn!("{warning}");
+                }
+                wsl_config_warnings = wsl_config.warnings().to_vec();
+            }
+            if wsl_config.dns_tunneling() {
+                reporter.report_stand_down(&config);
+                // WSL manages resolv.conf while standing down, so it needs updating afterwards
+                last_applied = None;
+                continue;
+            }
+            log::info!(
+                "Running due to {msg:?} message (and {debounced} debounced messages) for {targets:?}"
+            );
+            let mut record = RunRecord::new(msg.clone(), targets.clone(), debounced);
+            check_network(&config, &mut record, &mut reporter);
+            let run = Run {
+                targets: &targets,
+                lifecycle_only,
+                added: &added,
+                known: &known,
+                cancel: &cancel,
+                previous: last_applied.as_ref().filter(|_| network_only),
+                networking_mode: wsl_config.networking_mode,
+            };
+            cancel.reset();
+            // Taken after enforcing metrics and fixing routes, so that the notifications for
+            // those changes don't cancel the run
+            *started.lock().unwrap() = NetworkSnapshot::capture().ok();
+            let result = update_dns(&config, &run, &mut probe_cache, &mut record);
+            *started.lock().unwrap() = None;
+            record.finish(&result);
+            if let Ok(outcome) = &result {
+                if !outcome.skipped && outcome.failed.is_empty() && targets == Targets::All {
+                    last_applied = Some(outcome.snapshot.clone());
+                }
+            }
+            if config.logging.event_log {
+                event_log.write(&record);
+            }
+
+            // Retry any transient failures, unless they have already been retried enough
+            let retry =
+                retry_targets(&result, &targets).filter(|_| retries < config.retry.max_attempts);
+            if let Some(retry) = &retry {
+                let delay = backoff(&config.retry, retries);
+                log::warn!(
+                    "Transient failure, retrying {retry:?} in {delay:?} (retry {} of {})",
+                    retries + 1,
+                    config.retry.max_attempts
+                );
+                let request = RunRequest::new(RunReason::Retry(retries + 1), retry.clone());
+                schedule(&retry_tx, request, delay);
+            }
+            if network_change && config.retry.follow_up_secs > 0 {
+                let delay = Duration::from_secs(config.retry.follow_up_secs);
+                schedule(&retry_tx, RunReason::FollowUp.into(), delay);
+            }
+            reporter.report(&config, &msg, &result, retry.is_some());
+        }
+    });
 }
 
-pub fn start_runner(config: Config, rx: mpsc::Receiver<RunReason>, tray: TrayHandle) {
-    spawn(move || loop {
-        let msg = rx.recv().unwrap();
-        let timeout = Instant::now() + DEBOUNCE;
-        let mut debounced = 0;
-        while rx
-            .recv_timeout(timeout.saturating_duration_since(Instant::now()))
-            .is_ok()
-        {
-            debounced += 1;
+/// Reports the result of each run via the tray icon, tooltip and notifications
+struct StatusReporter {
+    tray: TrayHandle,
+    last_error_notification: Option<Instant>,
+    /// The DNS configuration applied by the last fully successful run
+    previous: Option<DnsConfiguration>,
+    /// Whether the user has been told that the agent isn't running as administrator
+    notified_not_elevated: bool,
+    /// Misconfiguration found before the last run
+    findings: Vec<Finding>,
+    /// Whether runs are being skipped because WSL is handling DNS itself
+    standing_down: bool,
+}
+
+impl StatusReporter {
+    fn new(tray: TrayHandle) -> Self {
+        Self {
+            tray,
+            last_error_notification: None,
+            previous: None,
+            notified_not_elevated: false,
+            findings: vec![],
+            standing_down: false,
+        }
+    }
+
+    /// WSL only reads .wslconfig when it starts, so the user is reminded to restart it
+    fn report_wslconfig_changes(&mut self, config: &Config, changes: &[String]) {
+        if changes.is_empty() {
+            return;
+        }
+        let message = format!(
+            "Updated .wslconfig ({}), run \"wsl --shutdown\" for the changes to take effect",
+            changes.join(", ")
+        );
+        log::info!("{message}");
+        if config.show_notifications {
+            self.tray.notify_info(&message);
+        }
+    }
+
+    /// With DNS tunneling, WSL answers DNS queries itself by forwarding them to Windows, so the
+    /// agent would only be fighting it
+    fn report_stand_down(&mut self, config: &Config) {
+        let time = chrono::Local::now().format("%H:%M:%S");
+        self.set_status(
+            TrayStatus::Ok,
+            &format!("Standing down at {time}, DNS tunneling is enabled in .wslconfig"),
+        );
+        if self.standing_down {
+            return;
+        }
+        self.standing_down = true;
+        log::info!("DNS tunneling is enabled in .wslconfig, not updating distributions");
+        if config.show_notifications {
+            self.tray.notify_info(
+                "WSL DNS tunneling is enabled, so the agent won't update distributions",
+            );
+        }
+    }
+
+    /// Sets the tray status, noting any configuration warnings in the tooltip
+    fn set_status(&self, status: TrayStatus, tooltip: &str) {
+        let warnings = self
+            .findings
+            .iter()
+            .filter(|f| f.severity == Severity::Warning)
+            .count();
+        if warnings == 0 {
+            self.tray.set_status(status, tooltip);
+        } else {
+            self.tray.set_status(
+                status,
+                &format!("{tooltip}\n{warnings} configuration warning(s), see log"),
+            );
+        }
+    }
+
+    /// Findings are only logged and notified when they are first seen
+    fn report_findings(&mut self, config: &Config, findings: &[Finding]) {
+        let new = findings
+            .iter()
+            .filter(|f| !self.findings.contains(f))
+            .collect::<Vec<_>>();
+        for finding in &new {
+            // Already logged by the routing check
+            if config.routing.enabled && finding.rule == Rule::WslRouteConflict {
+                continue;
+            }
+            match finding.severity {
+                Severity::Info => log::info!("{}", finding.describe()),
+                Severity::Warning => log::warn!("{}", finding.describe()),
+            }
+        }
+        let warnings = new
+            .iter()
+            .filter(|f| f.severity == Severity::Warning)
+            .map(|f| f.message.as_str())
+            .collect::<Vec<_>>();
+        if !warnings.is_empty() && config.diagnostics.notify {
+            self.tray.notify_info(&warnings.join("\n"));
+        }
+        self.findings = findings.to_vec();
+    }
+
+    /// Not running as administrator is only notified once, since it won't change
+    fn report_repair_error(&mut self, config: &Config, message: &str, not_elevated: bool) {
+        log::error!("{message}");
+        if not_elevated {
+            if self.notified_not_elevated {
+                return;
+            }
+            self.notified_not_elevated = true;
         }
-        log::info!("Running due to {msg:?} message (and {debounced} debounced messages)");
-        match update_dns(&config) {
-            Err(e) => log::error!("Error running: {e}"),
-            Ok(_) => {
-                if config.show_notifications {
-                    tray.notify_dns_updated();
+        self.notify_error(config, message);
+    }
+
+    /// Errors are only notified once there are no retries remaining
+    fn report(
+        &mut self,
+        config: &Config,
+        reason: &RunReason,
+        result: &Result<RunOutcome, Error>,
+        retrying: bool,
+    ) {
+        self.standing_down = false;
+        let time = chrono::Local::now().format("%H:%M:%S");
+        let failed = if retrying {
+            "failed (retrying)"
+        } else {
+            "failed"
+        };
+        match result {
+            Err(Error::Cancelled) => {
+                // A new run has already been queued
+                log::info!("Run was cancelled");
+            }
+            Err(e) => {
+                log::error!("Error running: {e}");
+                self.set_status(
+                    TrayStatus::Failed,
+                    &format!("Last run at {time} {failed}: {}", e.summary()),
+                );
+                if !retrying {
+                    self.notify_error(config, &format!("Failed to update DNS: {}", e.summary()));
                 }
             }
+            Ok(outcome) if outcome.skipped => {}
+            Ok(outcome) if !outcome.failed.is_empty() => {
+                let names = outcome.failed.iter().map(|(name, _)| name).join(", ");
+                self.set_status(
+                    TrayStatus::Failed,
+                    &format!("Last run at {time} {failed} for {names}"),
+                );
+                if retrying {
+                    return;
+                }
+                let message = outcome
+                    .failed
+                    .iter()
+                    .map(|(name, e)| format!("Failed to update DNS for {name}: {}", e.summary()))
+                    .join("\n");
+                self.notify_error(config, &message);
+            }
+            Ok(outcome) => {
+                let mut tooltip = format!(
+                    "Last run at {time} updated {} distribution(s)",
+                    outcome.updated.len()
+                );
+                if !outcome.unhealthy.is_empty() {
+                    let names = outcome.unhealthy.iter().map(|(name, _)| name).join(", ");
+                    tooltip.push_str(&format!("\nDNS health check failed for {names}"));
+                    let message = outcome
+                        .unhealthy
+                        .iter()
+                        .map(|(name, warning)| format!("Updated DNS for {name}, but {warning}"))
+                        .join("\n");
+                    self.notify_error(config, &message);
+                }
+                self.set_status(TrayStatus::Ok, &tooltip);
+                let previous = self.previous.replace(outcome.dns.clone());
+                if !config.show_notifications {
+                    return;
+                }
+                let unchanged = previous.as_ref() == Some(&outcome.dns);
+                if unchanged && !config.notify_unchanged && !matches!(reason, RunReason::TrayButton)
+                {
+                    log::info!("DNS configuration unchanged, not showing notification");
+                    return;
+                }
+                let message = match config.notification_verbosity {
+                    NotificationVerbosity::Minimal => "Updated WSL2 DNS configuration".to_string(),
+                    NotificationVerbosity::Summary => {
+                        outcome.dns.describe_changes(previous.as_ref())
+                    }
+                    NotificationVerbosity::Detailed => format!(
+                        "{} \u{2014} applied to {}",
+                        outcome.dns.describe_changes(previous.as_ref()),
+                        if outcome.updated.is_empty() {
+                            "no distributions".to_string()
+                        } else {
+                            outcome.updated.join(", ")
+                        }
+                    ),
+                };
+                self.tray.notify_info(&message);
+            }
         }
-    });
+    }
+
+    fn notify_error(&mut self, config: &Config, message: &str) {
+        if !config.show_error_notifications {
+            return;
+        }
+        let interval = Duration::from_secs(config.error_notification_interval);
+        if let Some(last) = self.last_error_notification {
+            if last.elapsed() < interval {
+                log::info!("Suppressing error notification, one was shown recently");
+                return;
+            }
+        }
+        self.last_error_notification = Some(Instant::now());
+        self.tray.notify_error(message);
+    }
 }
 
 #[derive(Debug, Error)]
@@ -65,40 +456,466 @@ enum Error {
     ),
     #[error("wsl.conf error: {0}")]
     WslConfError(String),
+    #[error("Timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("Cancelled by a newer network change")]
+    Cancelled,
 }
 
-fn update_dns(config: &Config) -> Result<(), Error> {
-    let dns = dns::get_configuration()?;
-    let resolv = dns.generate_resolv();
+impl Error {
+    /// Whether the error may go away by itself, e.g. while a VPN is connecting or WSL is starting
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::Dns(e) => e.is_transient(),
+            Error::Wsl(e) => e.is_transient(),
+            Error::Timeout(_) => true,
+            Error::WslConfError(_) | Error::Cancelled => false,
+        }
+    }
+
+    /// A short single line description of the error, suitable for a notification
+    fn summary(&self) -> String {
+        match self {
+            Error::Wsl(e) => e.summary(),
+            e => e.to_string(),
+        }
+    }
+}
+
+/// Enforces the interface metrics, checks for (and fixes) routes that conflict with WSL, and
+/// reports any other misconfiguration, before each run
+fn check_network(config: &Config, record: &mut RunRecord, reporter: &mut StatusReporter) {
+    if config.metrics.enabled {
+        match metrics::enforce(&config.metrics) {
+            Ok(changes) => record.metric_changes = changes,
+            Err(e) => reporter.report_repair_error(
+                config,
+                &format!("Failed to set interface metrics: {e}"),
+                matches!(e, metrics::Error::NotElevated),
+            ),
+        }
+    }
+    if config.routing.enabled {
+        match routing::check() {
+            Ok(conflicts) => {
+                if config.routing.fix && !conflicts.is_empty() {
+                    match routing::fix(&conflicts) {
+                        Ok(fixed) => log::info!("Fixed {fixed} conflicting route(s)"),
+                        Err(e) => reporter.report_repair_error(
+                            config,
+                            &format!("Failed to fix routes: {e}"),
+                            matches!(e, routing::Error::NotElevated),
+                        ),
+                    }
+                }
+                record.route_conflicts = conflicts;
+            }
+            Err(e) => log::error!("Failed to check routes: {e}"),
+        }
+    }
+    if config.diagnostics.enabled {
+        match diagnostics::check(config) {
+            Ok(findings) => {
+                reporter.report_findings(config, &findings);
+                record.findings = findings;
+            }
+            Err(e) => log::error!("Failed to check the network configuration: {e}"),
+        }
+    }
+}
+
+/// The DNS configuration applied during a run, and the distributions that were updated
+/// or failed to update
+#[derive(Debug)]
+struct RunOutcome {
+    dns: DnsConfiguration,
+    snapshot: DnsSnapshot,
+    /// Nothing relevant to DNS changed, or there were no DNS servers, so no distributions were
+    /// updated
+    skipped: bool,
+    updated: Vec<String>,
+    failed: Vec<(String, Error)>,
+    /// Distributions that were updated, but failed the health check
+    unhealthy: Vec<(String, String)>,
+}
+
+impl RunOutcome {
+    fn skipped(dns: DnsConfiguration, snapshot: DnsSnapshot) -> Self {
+        Self {
+            dns,
+            snapshot,
+            skipped: true,
+            updated: vec![],
+            failed: vec![],
+            unhealthy: vec![],
+        }
+    }
+}
+
+/// What a run should update
+struct Run<'a> {
+    targets: &'a Targets,
+    /// Only triggered by lifecycle events, so distributions that are no longer running, or
+    /// already have the current configuration, can be skipped
+    lifecycle_only: bool,
+    /// Distributions that were added, which are updated even if they aren't running
+    added: &'a BTreeSet<String>,
+    known: &'a KnownDistributions,
+    cancel: &'a CancelToken,
+    /// The last applied snapshot, when the run can be skipped if it hasn't changed
+    previous: Option<&'a DnsSnapshot>,
+    networking_mode: NetworkingMode,
+}
+
+/// Set when a run should stop early, which is checked between each step
+#[derive(Clone, Default)]
+struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        if self.0.load(Ordering::SeqCst) {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn update_dns(
+    config: &Config,
+    run: &Run,
+    probe_cache: &mut ProbeCache,
+    record: &mut RunRecord,
+) -> Result<RunOutcome, Error> {
+    let adapters = dns::get_internet_adapters(&config.adapters, run.networking_mode)?;
+    record.adapters = adapters.clone();
+    let mut dns = DnsConfiguration::from_adapters(&adapters, run.networking_mode);
+    run.cancel.check()?;
+    if config.probe.enabled {
+        let probes = probe_cache.probe_all(dns.servers(), probe::DNS_PORT, &config.probe);
+        let unreachable = probes
+            .iter()
+            .filter(|p| !p.reachable)
+            .map(|p| p.server)
+            .collect::<Vec<_>>();
+        if !unreachable.is_empty() {
+            log::warn!("Unreachable DNS servers: {unreachable:?}");
+            dns.prioritise_reachable(&unreachable, config.probe.drop_unreachable);
+        }
+        record.probes = probes;
+    }
+    record.dns = Some(dns.clone());
+    let snapshot = DnsSnapshot::new(&adapters, &dns);
     log::info!("Detected Windows DNS config: {dns:?}");
-    let wsl = wsl::get_distributions()?
+    let servers = dns.resolv_servers().copied().collect::<Vec<_>>();
+    if servers.is_empty() {
+        // Usually because Windows is offline, the distributions keep their current configuration
+        // until there are servers again
+        log::info!("Windows has no DNS servers, skipping");
+        record.skipped = true;
+        return Ok(RunOutcome::skipped(dns, snapshot));
+    }
+    if let Some(previous) = run.previous {
+        record.changes = snapshot.diff(previous);
+        if record.changes.is_empty() {
+            log::info!("No DNS relevant changes since the last update, skipping");
+            record.skipped = true;
+            return Ok(RunOutcome::skipped(dns, snapshot));
+        }
+        log::info!("DNS relevant changes: {}", record.changes.join("; "));
+    }
+    let resolv = dns.generate_resolv();
+    run.cancel.check()?;
+    let limits = config.wsl_limits();
+    let wsl = record
+        .calls
+        .time("wsl --list --verbose", || wsl::get_distributions(&limits))?
         .into_iter()
         .filter(|d| d.version == 2)
         .collect::<Vec<_>>();
     log::info!("Found {} WSL2 distributions", wsl.len());
-    for d in wsl {
-        let dist_config = config.get_distribution_setting(&d.name);
-        if dist_config.apply_dns {
-            log::info!("Updating DNS for {}", d.name);
-            if let Err(e) = update_distribution(&d, dist_config, &resolv) {
-                log::error!("Failed to update DNS for {}, due to: {}", d.name, e);
+    *run.known.lock().unwrap() = wsl.iter().map(|d| d.name.clone()).sorted().collect();
+    let wsl = wsl
+        .into_iter()
+        .filter(|d| run.targets.includes(&d.name))
+        .collect::<Vec<_>>();
+    let context = Arc::new(UpdateContext {
+        resolv,
+        servers,
+        health_check: config.health_check.clone(),
+        lifecycle_only: run.lifecycle_only,
+        added: run.added.clone(),
+        cancel: run.cancel.clone(),
+    });
+    let tasks = wsl
+        .iter()
+        .map(|d| {
+            let d = d.clone();
+            let dist_config = config.get_distribution_setting(&d.name).clone();
+            let context = context.clone();
+            move |limits: wsl::Limits| update_one(&d, &dist_config, &context, &limits)
+        })
+        .collect::<Vec<_>>();
+    let timeout = Duration::from_secs(config.distribution_timeout_secs);
+    let results = run_parallel(tasks, config.max_parallel_updates, timeout, &limits);
+
+    let mut outcome = RunOutcome {
+        dns,
+        snapshot,
+        skipped: false,
+        updated: vec![],
+        failed: vec![],
+        unhealthy: vec![],
+    };
+    for (d, result) in wsl.into_iter().zip(results) {
+        let (dist_record, error) = result.unwrap_or_else(|| {
+            log::error!("Timed out updating DNS for {}", d.name);
+            let mut dist_record = DistributionRecord::new(&d.name);
+            let error = Error::Timeout(timeout);
+            dist_record.action = DistributionAction::Failed;
+            dist_record.error = Some(error.to_string());
+            (dist_record, Some(error))
+        });
+        if let Some(warning) = &dist_record.warning {
+            outcome.unhealthy.push((d.name.clone(), warning.clone()));
+        }
+        match error {
+            Some(e) => outcome.failed.push((d.name, e)),
+            None if matches!(dist_record.action, DistributionAction::Updated) => {
+                outcome.updated.push(d.name)
             }
-        } else {
-            log::info!("Ignoring: {}", d.name);
+            None => {}
         }
+        record.distributions.push(dist_record);
     }
-    Ok(())
+    run.cancel.check()?;
+    Ok(outcome)
+}
+
+/// The distributions that should be retried, if the run or any distributions failed due to a
+/// transient error
+fn retry_targets(result: &Result<RunOutcome, Error>, targets: &Targets) -> Option<Targets> {
+    match result {
+        Err(e) if e.is_transient() => Some(targets.clone()),
+        Err(_) => None,
+        Ok(outcome) => {
+            let names = outcome
+                .failed
+                .iter()
+                .filter(|(_, e)| e.is_transient())
+                .map(|(name, _)| name.clone())
+                .collect::<BTreeSet<_>>();
+            (!names.is_empty()).then_some(Targets::Only(names))
+        }
+    }
+}
+
+/// Exponential backoff, with a random jitter of up to half the delay
+fn backoff(setting: &RetrySetting, retries: u32) -> Duration {
+    let delay = setting
+        .initial_delay_ms
+        .saturating_mul(1 << retries.min(16))
+        .min(setting.max_delay_ms);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let jitter = delay / 2 * u64::from(nanos % 1000) / 1000;
+    Duration::from_millis(delay - jitter)
 }
 
+/// Sends the request after a delay
+fn schedule(tx: &mpsc::Sender<RunRequest>, request: RunRequest, delay: Duration) {
+    let tx = tx.clone();
+    spawn(move || {
+        sleep(delay);
+        tx.send(request).ok();
+    });
+}
+
+/// Everything needed to update a distribution, shared between the worker threads
+struct UpdateContext {
+    resolv: String,
+    servers: Vec<IpAddr>,
+    health_check: HealthCheckSetting,
+    lifecycle_only: bool,
+    added: BTreeSet<String>,
+    cancel: CancelToken,
+}
+
+/// Updates a single distribution, returning what was done, and the error if it failed
+fn update_one(
+    d: &WslDistribution,
+    dist_config: &DistributionSetting,
+    context: &UpdateContext,
+    limits: &wsl::Limits,
+) -> (DistributionRecord, Option<Error>) {
+    let mut dist_record = DistributionRecord::new(&d.name);
+    if let Err(e) = context.cancel.check() {
+        return (dist_record, Some(e));
+    }
+    if !dist_config.apply_dns {
+        log::info!("Ignoring: {}", d.name);
+        return (dist_record, None);
+    }
+    if context.lifecycle_only && !d.is_running() && !context.added.contains(&d.name) {
+        // Reading resolv.conf would start it again, it will be updated when next started. Newly
+        // added distributions are usually stopped, but are updated anyway so that they have the
+        // current configuration from their first use
+        log::info!("Ignoring {} as it is no longer running", d.name);
+        return (dist_record, None);
+    }
+    if context.lifecycle_only && resolv_matches(d, context, limits, &mut dist_record.calls) {
+        log::info!("{} already has the current DNS configuration", d.name);
+        dist_record.action = DistributionAction::Unchanged;
+        return (dist_record, None);
+    }
+    log::info!("Updating DNS for {}", d.name);
+    match update_distribution(d, dist_config, context, limits, &mut dist_record) {
+        Ok(_) => {
+            if let Some(warning) = &dist_record.warning {
+                log::warn!("Updated DNS for {}, but {warning}", d.name);
+            }
+            dist_record.action = DistributionAction::Updated;
+            (dist_record, None)
+        }
+        Err(e) => {
+            log::error!("Failed to update DNS for {}, due to: {}", d.name, e);
+            dist_record.action = DistributionAction::Failed;
+            dist_record.error = Some(e.to_string());
+            (dist_record, Some(e))
+        }
+    }
+}
+
+/// Runs the tasks on up to `max_parallel` threads at once, returning their results in order
+///
+/// A task that takes longer than the timeout is aborted (its result will be `None`), so that one
+/// hung call to wsl.exe doesn't hold up the others. Aborted tasks are given a short time to stop
+/// before returning, so that they aren't still writing to a distribution when the next run starts.
+/// Every call to wsl.exe fails once aborted, so a task that doesn't stop in time is left to finish
+/// in the background rather than blocking the runner
+fn run_parallel<T, F>(
+    tasks: Vec<F>,
+    max_parallel: usize,
+    timeout: Duration,
+    limits: &wsl::Limits,
+) -> Vec<Option<T>>
+where
+    T: Send + 'static,
+    F: FnOnce(wsl::Limits) -> T + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let mut results = tasks.iter().map(|_| None).collect::<Vec<_>>();
+    let mut pending = tasks.into_iter().enumerate();
+    // Deadline, limits and thread of each task that is currently running
+    let mut running = HashMap::new();
+    // Thread of each task that timed out, until it stops
+    let mut aborted = HashMap::<usize, JoinHandle<_>>::new();
+    loop {
+        while running.len() < max_parallel.max(1) {
+            match pending.next() {
+                Some((index, task)) => {
+                    let tx = tx.clone();
+                    let limits = limits.abortable();
+                    let task_limits = limits.clone();
+                    let handle = spawn(move || tx.send((index, task(task_limits))).ok());
+                    running.insert(index, (Instant::now() + timeout, limits, handle));
+                }
+                None => break,
+            }
+        }
+        let (next, deadline) = match running.iter().min_by_key(|(_, (deadline, ..))| *deadline) {
+            Some((index, (deadline, ..))) => (*index, *deadline),
+            None => break,
+        };
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok((index, result)) => {
+                // Ignore the results of tasks that have already timed out
+                if running.remove(&index).is_some() {
+                    results[index] = Some(result);
+                } else if let Some(handle) = aborted.remove(&index) {
+                    handle.join().ok();
+                }
+            }
+            Err(_) => {
+                if let Some((_, limits, handle)) = running.remove(&next) {
+                    limits.abort();
+                    aborted.insert(next, handle);
+                }
+            }
+        }
+    }
+    // Each task sends its result just before it finishes, including those that were aborted
+    let grace = Instant::now() + ABORT_GRACE;
+    while !aborted.is_empty() {
+        match rx.recv_timeout(grace.saturating_duration_since(Instant::now())) {
+            Ok((index, _)) => {
+                if let Some(handle) = aborted.remove(&index) {
+                    handle.join().ok();
+                }
+            }
+            Err(_) => {
+                log::warn!(
+                    "{} timed out updates didn't stop, leaving them to finish in the background",
+                    aborted.len()
+                );
+                break;
+            }
+        }
+    }
+    results
+}
+
+/// Whether the distribution's resolv.conf is the same as the generated one, ignoring comments
+/// such as the timestamp
+fn resolv_matches(
+    distribution: &WslDistribution,
+    context: &UpdateContext,
+    limits: &wsl::Limits,
+    calls: &mut Calls,
+) -> bool {
+    fn significant_lines(contents: &str) -> Vec<&str> {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#') && !l.starts_with(';'))
+            .collect()
+    }
+    calls
+        .time(&format!("cat {RESOLV_CONF}"), || {
+            distribution.read_file(limits, RESOLV_CONF)
+        })
+        .map(|current| significant_lines(&current) == significant_lines(&context.resolv))
+        .unwrap_or(false)
+}
+
+/// Updates the distribution, recording the result of the health check (which doesn't fail the
+/// update, since resolv.conf has already been written)
 fn update_distribution(
     distribution: &WslDistribution,
     config: &DistributionSetting,
-    resolv: &str,
+    context: &UpdateContext,
+    limits: &wsl::Limits,
+    record: &mut DistributionRecord,
 ) -> Result<(), Error> {
+    let calls = &mut record.calls;
     // Ensure that generateResolvConf is disabled, otherwise further steps will fail
     if config.patch_wsl_conf {
         let mut config = Ini::new_cs();
-        let wsl_conf = distribution.read_file(WSL_CONF).ok();
+        let wsl_conf = calls
+            .time(&format!("cat {WSL_CONF}"), || {
+                distribution.read_file(limits, WSL_CONF)
+            })
+            .ok();
         let needs_update = if let Some(wsl_conf) = wsl_conf {
             config.read(wsl_conf).map_err(Error::WslConfError)?;
             config
@@ -112,24 +929,63 @@ fn update_distribution(
             log::warn!("Updating {} for {}", WSL_CONF, distribution.name);
             config.set("network", "generateResolvConf", Some("false".to_string()));
             let new_conf = config.writes().replace("\r\n", "\n");
-            distribution.write_file(WSL_CONF, &new_conf)?;
+            calls.time(&format!("tee {WSL_CONF}"), || {
+                distribution.write_file(limits, WSL_CONF, &new_conf)
+            })?;
             // Distribution needs to be restarted to take effect
-            distribution.terminate()?;
+            calls.time("--terminate", || distribution.terminate(limits))?;
         }
     }
 
+    context.cancel.check()?;
+
     // Replace the /etc/resolv.conf file
     // Removing read only is expected to fail if the file doesn't exist
     // Read only needs to be set because of bug:
     // https://github.com/microsoft/WSL/issues/6977
-    distribution.set_read_only(RESOLV_CONF, false).ok();
-    distribution.write_file(RESOLV_CONF, resolv)?;
-    distribution.set_read_only(RESOLV_CONF, true)?;
+    calls
+        .time(&format!("chattr -i {RESOLV_CONF}"), || {
+            distribution.set_read_only(limits, RESOLV_CONF, false)
+        })
+        .ok();
+    calls.time(&format!("tee {RESOLV_CONF}"), || {
+        distribution.write_file(limits, RESOLV_CONF, &context.resolv)
+    })?;
+    calls.time(&format!("chattr +i {RESOLV_CONF}"), || {
+        distribution.set_read_only(limits, RESOLV_CONF, true)
+    })?;
+
+    // Optionally check that DNS now works within the distribution
+    context.cancel.check()?;
+    if context.health_check.enabled {
+        match calls.time("health check", || {
+            health::check(
+                distribution,
+                limits,
+                &context.servers,
+                &context.health_check,
+            )
+        }) {
+            Ok(health) => {
+                if !health.is_healthy() {
+                    record.warning =
+                        Some(format!("the DNS health check failed: {}", health.summary()));
+                }
+                record.health = Some(health);
+            }
+            Err(e) => {
+                record.warning = Some(format!(
+                    "the DNS health check couldn't run: {}",
+                    e.summary()
+                ))
+            }
+        }
+    }
 
     // Optionally shutdown the WSL2 distribution once finished
     if config.shutdown && distribution.was_stopped() {
         log::info!("Terminating {}", distribution.name);
-        distribution.terminate()?;
+        calls.time("--terminate", || distribution.terminate(limits))?;
     }
 
     Ok(())
@@ -1,8 +1,14 @@
-use crate::{RunReason, APP_NAME};
+use crate::bundle;
+use crate::config::Config;
+use crate::request::{RunReason, RunRequest, Targets};
+use crate::runner::KnownDistributions;
+use crate::APP_NAME;
 use std::mem::size_of_val;
 use std::path::PathBuf;
 use std::ptr::null;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::spawn;
 use win32_utils::error::{check_error, CheckError};
 use win32_utils::macros::LOWORD;
 use win32_utils::str::ToWin32Str;
@@ -11,38 +17,75 @@ use windows::core::PCWSTR;
 use windows::Win32::Foundation::{HWND, LPARAM, LRESULT, POINT, WPARAM};
 use windows::Win32::System::LibraryLoader::GetModuleHandleW;
 use windows::Win32::UI::Shell::{
-    Shell_NotifyIconW, NIF_ICON, NIF_INFO, NIF_MESSAGE, NIF_TIP, NIM_ADD, NIM_DELETE, NIM_MODIFY,
-    NIM_SETVERSION, NIN_SELECT, NOTIFYICONDATAW, NOTIFYICON_VERSION_4,
+    Shell_NotifyIconW, NIF_ICON, NIF_INFO, NIF_MESSAGE, NIF_SHOWTIP, NIF_TIP, NIIF_ERROR, NIM_ADD,
+    NIM_DELETE, NIM_MODIFY, NIM_SETVERSION, NIN_SELECT, NOTIFYICONDATAW, NOTIFYICON_VERSION_4,
 };
 use windows::Win32::UI::WindowsAndMessaging::{
-    CreateIconFromResource, CreatePopupMenu, CreateWindowExW, DefWindowProcW, DispatchMessageW,
-    GetCursorPos, GetMessageW, InsertMenuW, PostQuitMessage, RegisterClassW, SendMessageW,
-    SetForegroundWindow, SetWindowLongPtrW, TrackPopupMenu, TranslateMessage, CW_USEDEFAULT,
-    GWLP_USERDATA, HMENU, MF_BYPOSITION, MF_STRING, MSG, TPM_BOTTOMALIGN, TPM_LEFTALIGN,
-    TPM_LEFTBUTTON, WINDOW_EX_STYLE, WM_APP, WM_COMMAND, WM_CONTEXTMENU, WNDCLASSW,
-    WS_OVERLAPPEDWINDOW,
+    AppendMenuW, CreateIconFromResource, CreatePopupMenu, CreateWindowExW, DefWindowProcW,
+    DispatchMessageW, GetCursorPos, GetMessageW, InsertMenuW, PostQuitMessage, RegisterClassW,
+    SendMessageW, SetForegroundWindow, SetWindowLongPtrW, TrackPopupMenu, TranslateMessage,
+    CW_USEDEFAULT, GWLP_USERDATA, HICON, HMENU, MF_BYPOSITION, MF_POPUP, MF_STRING, MSG,
+    TPM_BOTTOMALIGN, TPM_LEFTALIGN, TPM_LEFTBUTTON, WINDOW_EX_STYLE, WM_APP, WM_COMMAND,
+    WM_CONTEXTMENU, WNDCLASSW, WS_OVERLAPPEDWINDOW,
 };
 
 const ICON_BYTES: &[u8] = include_bytes!("../assets/icon.png");
+const ICON_ERROR_BYTES: &[u8] = include_bytes!("../assets/icon_error.png");
+const ICON_PAUSED_BYTES: &[u8] = include_bytes!("../assets/icon_paused.png");
 
 const IDM_EXIT: usize = 100;
 const IDM_SHOW_LOG: usize = 101;
 const IDM_UPDATE_DNS: usize = 102;
+const IDM_PAUSE: usize = 103;
+const IDM_EDIT_CONFIG: usize = 104;
+const IDM_RELOAD_CONFIG: usize = 105;
+const IDM_CREATE_BUNDLE: usize = 106;
+/// Reapply DNS to a single distribution, offset by its index in the menu
+const IDM_UPDATE_DISTRIBUTION: usize = 1000;
 
 const TRAY_ICON_CALLBACK: u32 = WM_APP + 1;
-const NOTIFY_DNS_UPDATED: u32 = WM_APP + 2;
+const NOTIFY_INFO: u32 = WM_APP + 2;
+const NOTIFY_ERROR: u32 = WM_APP + 3;
+const SET_STATUS: u32 = WM_APP + 4;
+
+/// The health of the agent, as shown by the tray icon
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TrayStatus {
+    Ok = 0,
+    Failed = 1,
+}
+
+struct TrayIcons {
+    ok: HICON,
+    failed: HICON,
+    paused: HICON,
+}
 
 struct TrayProperties {
     log_file_path: PathBuf,
-    sender: mpsc::Sender<RunReason>,
+    config_file_path: PathBuf,
+    sender: mpsc::Sender<RunRequest>,
+    paused: Arc<AtomicBool>,
+    known: KnownDistributions,
+    /// The distributions listed in the menu when it was last shown
+    menu_distributions: Vec<String>,
     window: HWND,
     icon: NOTIFYICONDATAW,
+    icons: TrayIcons,
+    status: TrayStatus,
+    tooltip: String,
 }
 
 pub struct Tray(Box<TrayProperties>);
 
 impl Tray {
-    pub fn new(log_file_path: PathBuf, sender: mpsc::Sender<RunReason>) -> Self {
+    pub fn new(
+        log_file_path: PathBuf,
+        config_file_path: PathBuf,
+        sender: mpsc::Sender<RunRequest>,
+        paused: Arc<AtomicBool>,
+        known: KnownDistributions,
+    ) -> Self {
         unsafe {
             // Create Window Class
             let hinstance = GetModuleHandleW(PCWSTR::default()).unwrap();
@@ -74,36 +117,41 @@ impl Tray {
             .check_error()
             .unwrap();
 
+            // Create hicons
+            let icons = TrayIcons {
+                ok: load_icon(ICON_BYTES),
+                failed: load_icon(ICON_ERROR_BYTES),
+                paused: load_icon(ICON_PAUSED_BYTES),
+            };
+
             // Register Window data
             let mut window_data = Box::new(TrayProperties {
                 log_file_path,
+                config_file_path,
                 sender,
+                paused,
+                known,
+                menu_distributions: Vec::new(),
                 window: hwnd,
                 icon: NOTIFYICONDATAW::default(),
+                icons,
+                status: TrayStatus::Ok,
+                tooltip: String::new(),
             });
             check_error(|| {
                 SetWindowLongPtrW(hwnd, GWLP_USERDATA, window_data.as_mut() as *mut _ as isize)
             })
             .unwrap();
 
-            // Create hicon
-            let hicon = CreateIconFromResource(
-                ICON_BYTES.as_ptr(),
-                ICON_BYTES.len() as u32,
-                true,
-                0x00030000,
-            )
-            .unwrap();
-
             // Create tray icon
             window_data.icon.cbSize = size_of_val(&window_data.icon) as u32;
             window_data.icon.hWnd = hwnd;
-            window_data.icon.hIcon = hicon;
+            window_data.icon.hIcon = window_data.icons.ok;
             window_data.icon.uCallbackMessage = TRAY_ICON_CALLBACK;
             APP_NAME
                 .copy_to_wchar_buffer(&mut window_data.icon.szTip)
                 .unwrap();
-            window_data.icon.uFlags = NIF_ICON | NIF_MESSAGE | NIF_TIP;
+            window_data.icon.uFlags = NIF_ICON | NIF_MESSAGE | NIF_TIP | NIF_SHOWTIP;
             window_data.icon.Anonymous.uVersion = NOTIFYICON_VERSION_4;
 
             Shell_NotifyIconW(NIM_ADD, &window_data.icon).ok().unwrap();
@@ -145,14 +193,59 @@ impl Tray {
 #[derive(Clone)]
 pub struct TrayHandle(HWND);
 
+// SendMessageW() blocks until the message has been processed, so it is safe to pass
+// references to the tray window via the LPARAM
 impl TrayHandle {
-    pub fn notify_dns_updated(&self) {
+    pub fn notify_info(&self, message: &str) {
+        unsafe {
+            SendMessageW(
+                self.0,
+                NOTIFY_INFO,
+                WPARAM(0),
+                LPARAM(&message as *const &str as isize),
+            );
+        }
+    }
+
+    pub fn notify_error(&self, message: &str) {
+        unsafe {
+            SendMessageW(
+                self.0,
+                NOTIFY_ERROR,
+                WPARAM(0),
+                LPARAM(&message as *const &str as isize),
+            );
+        }
+    }
+
+    /// Updates the tray icon and sets a tooltip summarising the last run
+    pub fn set_status(&self, status: TrayStatus, tooltip: &str) {
         unsafe {
-            SendMessageW(self.0, NOTIFY_DNS_UPDATED, WPARAM(0), LPARAM(0));
+            SendMessageW(
+                self.0,
+                SET_STATUS,
+                WPARAM(status as usize),
+                LPARAM(&tooltip as *const &str as isize),
+            );
         }
     }
 }
 
+unsafe fn load_icon(bytes: &[u8]) -> HICON {
+    CreateIconFromResource(bytes.as_ptr(), bytes.len() as u32, true, 0x00030000).unwrap()
+}
+
+/// Copies a string into a fixed size null terminated buffer, truncating it if needed
+fn copy_truncated(message: &str, buffer: &mut [u16]) {
+    let mut wide = message.encode_utf16().collect::<Vec<_>>();
+    if wide.len() >= buffer.len() {
+        wide.truncate(buffer.len() - 4);
+        wide.extend("...".encode_utf16());
+    }
+    buffer[..wide.len()].copy_from_slice(&wide);
+    buffer[wide.len()] = 0;
+}
+
 unsafe extern "system" fn tray_window_proc(
     hwnd: HWND,
     msg: u32,
@@ -167,8 +260,21 @@ unsafe extern "system" fn tray_window_proc(
                 }
                 _ => {}
             },
-            NOTIFY_DNS_UPDATED => {
-                properties.show_notification("Updated WSL2 DNS configuration");
+            NOTIFY_INFO => {
+                let message = &*(l_param.0 as *const &str);
+                properties.show_notification(message, false);
+            }
+            NOTIFY_ERROR => {
+                let message = &*(l_param.0 as *const &str);
+                properties.show_notification(message, true);
+            }
+            SET_STATUS => {
+                properties.status = match w_param.0 {
+                    0 => TrayStatus::Ok,
+                    _ => TrayStatus::Failed,
+                };
+                properties.tooltip = (*(l_param.0 as *const &str)).to_string();
+                properties.update_icon();
             }
             WM_COMMAND => {
                 properties.handle_command(w_param);
@@ -180,19 +286,41 @@ unsafe extern "system" fn tray_window_proc(
 }
 
 impl TrayProperties {
-    unsafe fn show_notification(&mut self, message: &str) {
+    unsafe fn show_notification(&mut self, message: &str, error: bool) {
         // NIF_INFO = Display a balloon notification
         self.icon.uFlags = NIF_INFO;
-        self.icon.dwInfoFlags = 0;
+        self.icon.dwInfoFlags = if error { NIIF_ERROR } else { 0 };
         APP_NAME
             .copy_to_wchar_buffer(&mut self.icon.szInfoTitle)
             .unwrap();
-        message.copy_to_wchar_buffer(&mut self.icon.szInfo).unwrap();
+        copy_truncated(message, &mut self.icon.szInfo);
         // https://github.com/jacob-pro/wsl2-dns-agent/issues/9
         Shell_NotifyIconW(NIM_MODIFY, &self.icon);
     }
 
-    unsafe fn show_tray_menu(&self) {
+    /// Redraws the tray icon and tooltip according to the current status
+    unsafe fn update_icon(&mut self) {
+        let (hicon, tooltip) = if self.paused.load(Ordering::SeqCst) {
+            (self.icons.paused, "Paused".to_string())
+        } else {
+            let hicon = match self.status {
+                TrayStatus::Ok => self.icons.ok,
+                TrayStatus::Failed => self.icons.failed,
+            };
+            (hicon, self.tooltip.clone())
+        };
+        let tooltip = if tooltip.is_empty() {
+            APP_NAME.to_string()
+        } else {
+            format!("{APP_NAME}\n{tooltip}")
+        };
+        self.icon.uFlags = NIF_ICON | NIF_TIP | NIF_SHOWTIP;
+        self.icon.hIcon = hicon;
+        copy_truncated(&tooltip, &mut self.icon.szTip);
+        Shell_NotifyIconW(NIM_MODIFY, &self.icon);
+    }
+
+    unsafe fn show_tray_menu(&mut self) {
         let mut pt = POINT::default();
         GetCursorPos(&mut pt);
         let hmenu = CreatePopupMenu().unwrap();
@@ -204,6 +332,43 @@ impl TrayProperties {
             IDM_EXIT,
             PCWSTR(exit_msg.as_ptr()),
         );
+        let pause_msg = if self.paused.load(Ordering::SeqCst) {
+            "Resume"
+        } else {
+            "Pause"
+        }
+        .to_wchar();
+        InsertMenuW(
+            hmenu,
+            0,
+            MF_BYPOSITION | MF_STRING,
+            IDM_PAUSE,
+            PCWSTR(pause_msg.as_ptr()),
+        );
+        let create_bundle_msg = "Create Diagnostics Bundle".to_wchar();
+        InsertMenuW(
+            hmenu,
+            0,
+            MF_BYPOSITION | MF_STRING,
+            IDM_CREATE_BUNDLE,
+            PCWSTR(create_bundle_msg.as_ptr()),
+        );
+        let reload_config_msg = "Reload Config".to_wchar();
+        InsertMenuW(
+            hmenu,
+            0,
+            MF_BYPOSITION | MF_STRING,
+            IDM_RELOAD_CONFIG,
+            PCWSTR(reload_config_msg.as_ptr()),
+        );
+        let edit_config_msg = "Edit Config".to_wchar();
+        InsertMenuW(
+            hmenu,
+            0,
+            MF_BYPOSITION | MF_STRING,
+            IDM_EDIT_CONFIG,
+            PCWSTR(edit_config_msg.as_ptr()),
+        );
         let view_log_msg = "View Log".to_wchar();
         InsertMenuW(
             hmenu,
@@ -212,6 +377,27 @@ impl TrayProperties {
             IDM_SHOW_LOG,
             PCWSTR(view_log_msg.as_ptr()),
         );
+        self.menu_distributions = self.known.lock().unwrap().clone();
+        if !self.menu_distributions.is_empty() {
+            let submenu = CreatePopupMenu().unwrap();
+            for (index, name) in self.menu_distributions.iter().enumerate() {
+                let name_msg = name.to_wchar();
+                AppendMenuW(
+                    submenu,
+                    MF_STRING,
+                    IDM_UPDATE_DISTRIBUTION + index,
+                    PCWSTR(name_msg.as_ptr()),
+                );
+            }
+            let reapply_to_msg = "Reapply DNS to".to_wchar();
+            InsertMenuW(
+                hmenu,
+                0,
+                MF_BYPOSITION | MF_POPUP,
+                submenu.0 as usize,
+                PCWSTR(reapply_to_msg.as_ptr()),
+            );
+        }
         let reapply_dns_msg = "Reapply DNS".to_wchar();
         InsertMenuW(
             hmenu,
@@ -232,15 +418,61 @@ impl TrayProperties {
         );
     }
 
-    unsafe fn handle_command(&self, w_param: WPARAM) {
+    unsafe fn handle_command(&mut self, w_param: WPARAM) {
         match w_param.0 {
             IDM_EXIT => PostQuitMessage(0),
             IDM_UPDATE_DNS => {
-                self.sender.send(RunReason::TrayButton).ok();
+                self.sender.send(RunReason::TrayButton.into()).ok();
             }
             IDM_SHOW_LOG => {
                 open::that(&self.log_file_path).ok();
             }
+            IDM_EDIT_CONFIG => {
+                open::that(&self.config_file_path).ok();
+            }
+            IDM_RELOAD_CONFIG => {
+                self.sender.send(RunReason::ConfigReload.into()).ok();
+            }
+            IDM_CREATE_BUNDLE => {
+                let tray = TrayHandle(self.window);
+                // Collecting the bundle requires calls to wsl.exe which may be slow
+                spawn(move || {
+                    let config = Config::try_load().unwrap_or_default();
+                    let path = bundle::default_path();
+                    match bundle::create(&path, &config.bundle, &config.wsl_limits()) {
+                        Ok(_) => {
+                            log::info!("Saved diagnostics bundle to: {}", path.display());
+                            tray.notify_info(&format!(
+                                "Saved diagnostics bundle to: {}",
+                                path.display()
+                            ));
+                            if let Some(parent) = path.parent() {
+                                open::that(parent).ok();
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Failed to create diagnostics bundle: {e}");
+                            tray.notify_error(&format!("Failed to create diagnostics bundle: {e}"));
+                        }
+                    }
+                });
+            }
+            IDM_PAUSE => {
+                let paused = !self.paused.load(Ordering::SeqCst);
+                self.paused.store(paused, Ordering::SeqCst);
+                log::info!("{}", if paused { "Paused" } else { "Resumed" });
+                self.update_icon();
+                if !paused {
+                    // Catch up on any changes that were missed while paused
+                    self.sender.send(RunReason::TrayButton.into()).ok();
+                }
+            }
+            id if id >= IDM_UPDATE_DISTRIBUTION => {
+                if let Some(name) = self.menu_distributions.get(id - IDM_UPDATE_DISTRIBUTION) {
+                    let request = RunRequest::new(RunReason::TrayButton, Targets::only(name));
+                    self.sender.send(request).ok();
+                }
+            }
             _ => {}
         }
     }
@@ -0,0 +1,77 @@
+use crate::config::WatchSetting;
+use crate::request::{RunReason, RunRequest};
+use crate::runner::KnownDistributions;
+use crate::wsl;
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+/// Polls `wsl --list --verbose`, requesting a run for each distribution that is added or started
+///
+/// The interval is reset to the minimum whenever a change is seen, and doubles (up to the maximum)
+/// each time nothing changes, or the list fails
+pub fn start_watcher(
+    setting: WatchSetting,
+    limits: wsl::Limits,
+    tx: Sender<RunRequest>,
+    known: KnownDistributions,
+) {
+    if !setting.enabled {
+        return;
+    }
+    let min_interval = Duration::from_secs(setting.min_interval_secs.max(1));
+    let max_interval = Duration::from_secs(setting.max_interval_secs).max(min_interval);
+    spawn(move || {
+        let mut interval = min_interval;
+        // Whether each WSL2 distribution was running, as of the last successful poll
+        let mut previous: Option<HashMap<String, bool>> = None;
+        loop {
+            match wsl::get_distributions(&limits) {
+                Ok(distributions) => {
+                    let current = distributions
+                        .into_iter()
+                        .filter(|d| d.version == 2)
+                        .map(|d| {
+                            let running = d.is_running();
+                            (d.name, running)
+                        })
+                        .collect::<HashMap<_, _>>();
+                    // The first poll only establishes the initial state
+                    *known.lock().unwrap() = current.keys().cloned().sorted().collect();
+                    let changed = previous.as_ref().map(|p| p != &current).unwrap_or(false);
+                    for event in previous.iter().flat_map(|p| diff(p, &current)) {
+                        log::info!("Distribution lifecycle event: {event:?}");
+                        if tx.send(event.into()).is_err() {
+                            return;
+                        }
+                    }
+                    previous = Some(current);
+                    interval = if changed {
+                        min_interval
+                    } else {
+                        (interval * 2).min(max_interval)
+                    };
+                }
+                Err(e) => {
+                    log::warn!("Unable to list distributions: {}", e.summary());
+                    interval = (interval * 2).min(max_interval);
+                }
+            }
+            sleep(interval);
+        }
+    });
+}
+
+/// Distributions that are new since the previous poll, or were not previously running
+fn diff(previous: &HashMap<String, bool>, current: &HashMap<String, bool>) -> Vec<RunReason> {
+    current
+        .iter()
+        .filter_map(|(name, running)| match previous.get(name) {
+            None => Some(RunReason::DistributionAdded(name.clone())),
+            Some(false) if *running => Some(RunReason::DistributionStarted(name.clone())),
+            _ => None,
+        })
+        .collect()
+}
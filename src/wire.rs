@@ -0,0 +1,725 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use thiserror::Error;
+
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+pub const CLASS_IN: u16 = 1;
+/// Recommended EDNS0 UDP payload size, avoiding IP fragmentation
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("Message ended unexpectedly")]
+    Truncated,
+    #[error("Invalid name compression pointer")]
+    InvalidPointer,
+    #[error("Unsupported label type: {0:#x}")]
+    InvalidLabelType(u8),
+    #[error("Label exceeds 63 bytes")]
+    LabelTooLong,
+    #[error("Name exceeds 255 bytes")]
+    NameTooLong,
+    #[error("Record data is {0} bytes, which exceeds the maximum length")]
+    DataTooLong(usize),
+    #[error("Record data doesn't match its type")]
+    InvalidData,
+    #[error("Message contains more than one OPT record")]
+    MultipleOpt,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Ns,
+    Cname,
+    Soa,
+    Aaaa,
+    Srv,
+    Opt,
+    Other(u16),
+}
+
+impl From<u16> for RecordType {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => RecordType::A,
+            2 => RecordType::Ns,
+            5 => RecordType::Cname,
+            6 => RecordType::Soa,
+            28 => RecordType::Aaaa,
+            33 => RecordType::Srv,
+            41 => RecordType::Opt,
+            other => RecordType::Other(other),
+        }
+    }
+}
+
+impl From<RecordType> for u16 {
+    fn from(value: RecordType) -> Self {
+        match value {
+            RecordType::A => 1,
+            RecordType::Ns => 2,
+            RecordType::Cname => 5,
+            RecordType::Soa => 6,
+            RecordType::Aaaa => 28,
+            RecordType::Srv => 33,
+            RecordType::Opt => 41,
+            RecordType::Other(other) => other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Header {
+    pub id: u16,
+    pub response: bool,
+    pub opcode: u8,
+    pub authoritative: bool,
+    pub truncated: bool,
+    pub recursion_desired: bool,
+    pub recursion_available: bool,
+    /// The lower 4 bits of the response code, see also [Edns::extended_rcode]
+    pub rcode: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: RecordType,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ns(String),
+    Cname(String),
+    Soa {
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Other(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub rtype: RecordType,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+/// The contents of an OPT pseudo-record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    /// The upper 8 bits of the response code
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<(u16, Vec<u8>)>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Self {
+            udp_payload_size: EDNS_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![],
+        }
+    }
+}
+
+/// A DNS message (RFC 1035), with support for EDNS0 (RFC 6891)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub header: Header,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    /// Additional records, excluding any OPT record which is decoded into `edns`
+    pub additionals: Vec<Record>,
+    pub edns: Option<Edns>,
+}
+
+impl Message {
+    /// A recursive query for a single name, advertising EDNS0 support
+    pub fn query(id: u16, name: &str, qtype: RecordType) -> Self {
+        Self {
+            header: Header {
+                id,
+                recursion_desired: true,
+                ..Default::default()
+            },
+            questions: vec![Question {
+                name: name.to_string(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            edns: Some(Edns::default()),
+            ..Default::default()
+        }
+    }
+
+    /// Whether this message is a response to the query
+    pub fn is_response_to(&self, query: &Message) -> bool {
+        self.header.response && self.header.id == query.header.id
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::with_capacity(512);
+        let h = &self.header;
+        let additional_count = self.additionals.len() + self.edns.is_some() as usize;
+        out.extend(h.id.to_be_bytes());
+        out.push(
+            (h.response as u8) << 7
+                | (h.opcode & 0x0F) << 3
+                | (h.authoritative as u8) << 2
+                | (h.truncated as u8) << 1
+                | h.recursion_desired as u8,
+        );
+        out.push((h.recursion_available as u8) << 7 | (h.rcode & 0x0F));
+        for count in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            additional_count,
+        ] {
+            out.extend((count as u16).to_be_bytes());
+        }
+        for question in &self.questions {
+            encode_name(&mut out, &question.name)?;
+            out.extend(u16::from(question.qtype).to_be_bytes());
+            out.extend(question.qclass.to_be_bytes());
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            encode_record(&mut out, record)?;
+        }
+        if let Some(edns) = &self.edns {
+            encode_record(&mut out, &edns.to_record())?;
+        }
+        Ok(out)
+    }
+
+    pub fn decode(message: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader {
+            message,
+            position: 0,
+        };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let header = Header {
+            id,
+            response: flags & 0x8000 != 0,
+            opcode: ((flags >> 11) & 0x0F) as u8,
+            authoritative: flags & 0x0400 != 0,
+            truncated: flags & 0x0200 != 0,
+            recursion_desired: flags & 0x0100 != 0,
+            recursion_available: flags & 0x0080 != 0,
+            rcode: (flags & 0x000F) as u8,
+        };
+        let question_count = reader.u16()?;
+        let answer_count = reader.u16()?;
+        let authority_count = reader.u16()?;
+        let additional_count = reader.u16()?;
+        let mut questions = Vec::new();
+        for _ in 0..question_count {
+            questions.push(Question {
+                name: reader.name()?,
+                qtype: reader.u16()?.into(),
+                qclass: reader.u16()?,
+            });
+        }
+        let mut read_records = |count: u16| -> Result<Vec<Record>, Error> {
+            (0..count).map(|_| reader.record()).collect()
+        };
+        let answers = read_records(answer_count)?;
+        let authorities = read_records(authority_count)?;
+        let mut additionals = read_records(additional_count)?;
+        let mut edns = None;
+        for opt in additionals.iter().filter(|r| r.rtype == RecordType::Opt) {
+            if edns.is_some() {
+                return Err(Error::MultipleOpt);
+            }
+            edns = Some(Edns::from_record(opt)?);
+        }
+        additionals.retain(|r| r.rtype != RecordType::Opt);
+        Ok(Message {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+            edns,
+        })
+    }
+}
+
+impl Edns {
+    fn to_record(&self) -> Record {
+        let mut data = Vec::new();
+        for (code, value) in &self.options {
+            data.extend(code.to_be_bytes());
+            data.extend((value.len() as u16).to_be_bytes());
+            data.extend(value);
+        }
+        Record {
+            name: String::new(),
+            rtype: RecordType::Opt,
+            class: self.udp_payload_size,
+            ttl: (self.extended_rcode as u32) << 24
+                | (self.version as u32) << 16
+                | (self.dnssec_ok as u32) << 15,
+            data: RecordData::Other(data),
+        }
+    }
+
+    fn from_record(record: &Record) -> Result<Self, Error> {
+        let data = match &record.data {
+            RecordData::Other(data) => data,
+            _ => return Err(Error::InvalidData),
+        };
+        let mut reader = Reader {
+            message: data,
+            position: 0,
+        };
+        let mut options = Vec::new();
+        while reader.position < data.len() {
+            let code = reader.u16()?;
+            let length = reader.u16()? as usize;
+            options.push((code, reader.bytes(length)?.to_vec()));
+        }
+        Ok(Self {
+            udp_payload_size: record.class,
+            extended_rcode: (record.ttl >> 24) as u8,
+            version: (record.ttl >> 16) as u8,
+            dnssec_ok: record.ttl & 0x8000 != 0,
+            options,
+        })
+    }
+}
+
+/// Names are always encoded without compression
+fn encode_name(out: &mut Vec<u8>, name: &str) -> Result<(), Error> {
+    let mut length = 1;
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        if label.len() > MAX_LABEL_LEN {
+            return Err(Error::LabelTooLong);
+        }
+        length += label.len() + 1;
+        if length > MAX_NAME_LEN {
+            return Err(Error::NameTooLong);
+        }
+        out.push(label.len() as u8);
+        out.extend(label.as_bytes());
+    }
+    out.push(0);
+    Ok(())
+}
+
+fn encode_record(out: &mut Vec<u8>, record: &Record) -> Result<(), Error> {
+    encode_name(out, &record.name)?;
+    out.extend(u16::from(record.rtype).to_be_bytes());
+    out.extend(record.class.to_be_bytes());
+    out.extend(record.ttl.to_be_bytes());
+    let mut data = Vec::new();
+    match &record.data {
+        RecordData::A(ip) => data.extend(ip.octets()),
+        RecordData::Aaaa(ip) => data.extend(ip.octets()),
+        RecordData::Ns(name) | RecordData::Cname(name) => encode_name(&mut data, name)?,
+        RecordData::Soa {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        } => {
+            encode_name(&mut data, mname)?;
+            encode_name(&mut data, rname)?;
+            for value in [serial, refresh, retry, expire, minimum] {
+                data.extend(value.to_be_bytes());
+            }
+        }
+        RecordData::Srv {
+            priority,
+            weight,
+            port,
+            target,
+        } => {
+            data.extend(priority.to_be_bytes());
+            data.extend(weight.to_be_bytes());
+            data.extend(port.to_be_bytes());
+            encode_name(&mut data, target)?;
+        }
+        RecordData::Other(bytes) => data.extend(bytes),
+    }
+    let length = u16::try_from(data.len()).map_err(|_| Error::DataTooLong(data.len()))?;
+    out.extend(length.to_be_bytes());
+    out.extend(data);
+    Ok(())
+}
+
+struct Reader<'a> {
+    message: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        let end = self.position.checked_add(length).ok_or(Error::Truncated)?;
+        let bytes = self
+            .message
+            .get(self.position..end)
+            .ok_or(Error::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a possibly compressed name, compression pointers must point strictly backwards
+    /// which prevents loops
+    fn name(&mut self) -> Result<String, Error> {
+        let mut labels = Vec::new();
+        let mut length = 1;
+        let mut position = self.position;
+        // Where to continue reading from once the name is complete
+        let mut resume = None;
+        loop {
+            let label_start = position;
+            let len = *self.message.get(position).ok_or(Error::Truncated)? as usize;
+            match len & 0xC0 {
+                0x00 => {
+                    position += 1;
+                    if len == 0 {
+                        break;
+                    }
+                    let label = self
+                        .message
+                        .get(position..position + len)
+                        .ok_or(Error::Truncated)?;
+                    length += len + 1;
+                    if length > MAX_NAME_LEN {
+                        return Err(Error::NameTooLong);
+                    }
+                    labels.push(String::from_utf8_lossy(label).to_string());
+                    position += len;
+                }
+                0xC0 => {
+                    let low = *self.message.get(position + 1).ok_or(Error::Truncated)?;
+                    let target = (len & 0x3F) << 8 | low as usize;
+                    if target >= label_start {
+                        return Err(Error::InvalidPointer);
+                    }
+                    resume.get_or_insert(position + 2);
+                    position = target;
+                }
+                _ => return Err(Error::InvalidLabelType(len as u8)),
+            }
+        }
+        self.position = resume.unwrap_or(position);
+        Ok(labels.join("."))
+    }
+
+    fn record(&mut self) -> Result<Record, Error> {
+        let name = self.name()?;
+        let rtype = RecordType::from(self.u16()?);
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let length = self.u16()? as usize;
+        let start = self.position;
+        let end = start + length;
+        if end > self.message.len() {
+            return Err(Error::Truncated);
+        }
+        let data = match rtype {
+            RecordType::A => RecordData::A(Ipv4Addr::from(
+                <[u8; 4]>::try_from(self.bytes(length)?).map_err(|_| Error::InvalidData)?,
+            )),
+            RecordType::Aaaa => RecordData::Aaaa(Ipv6Addr::from(
+                <[u8; 16]>::try_from(self.bytes(length)?).map_err(|_| Error::InvalidData)?,
+            )),
+            RecordType::Ns => RecordData::Ns(self.name()?),
+            RecordType::Cname => RecordData::Cname(self.name()?),
+            RecordType::Soa => RecordData::Soa {
+                mname: self.name()?,
+                rname: self.name()?,
+                serial: self.u32()?,
+                refresh: self.u32()?,
+                retry: self.u32()?,
+                expire: self.u32()?,
+                minimum: self.u32()?,
+            },
+            RecordType::Srv => RecordData::Srv {
+                priority: self.u16()?,
+                weight: self.u16()?,
+                port: self.u16()?,
+                target: self.name()?,
+            },
+            RecordType::Opt | RecordType::Other(_) => {
+                RecordData::Other(self.bytes(length)?.to_vec())
+            }
+        };
+        if self.position != end {
+            return Err(Error::InvalidData);
+        }
+        Ok(Record {
+            name,
+            rtype,
+            class,
+            ttl,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, data: RecordData) -> Record {
+        let rtype = match &data {
+            RecordData::A(_) => RecordType::A,
+            RecordData::Aaaa(_) => RecordType::Aaaa,
+            RecordData::Ns(_) => RecordType::Ns,
+            RecordData::Cname(_) => RecordType::Cname,
+            RecordData::Soa { .. } => RecordType::Soa,
+            RecordData::Srv { .. } => RecordType::Srv,
+            RecordData::Other(_) => RecordType::Other(16),
+        };
+        Record {
+            name: name.to_string(),
+            rtype,
+            class: CLASS_IN,
+            ttl: 300,
+            data,
+        }
+    }
+
+    fn response() -> Message {
+        let mut message = Message::query(0x1234, "www.example.com", RecordType::A);
+        message.header.response = true;
+        message.header.recursion_available = true;
+        message.answers = vec![
+            record(
+                "www.example.com",
+                RecordData::Cname("example.com".to_string()),
+            ),
+            record("example.com", RecordData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            record(
+                "example.com",
+                RecordData::Aaaa("2001:db8::1".parse().unwrap()),
+            ),
+            record(
+                "_ldap._tcp.example.com",
+                RecordData::Srv {
+                    priority: 10,
+                    weight: 20,
+                    port: 389,
+                    target: "dc.example.com".to_string(),
+                },
+            ),
+        ];
+        message.authorities = vec![record(
+            "example.com",
+            RecordData::Soa {
+                mname: "ns1.example.com".to_string(),
+                rname: "hostmaster.example.com".to_string(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            },
+        )];
+        message.additionals = vec![
+            record("example.com", RecordData::Ns("ns1.example.com".to_string())),
+            record("example.com", RecordData::Other(b"\x05hello".to_vec())),
+        ];
+        message
+    }
+
+    #[test]
+    fn round_trips_records() {
+        let message = response();
+        let decoded = Message::decode(&message.encode().unwrap()).unwrap();
+        assert_eq!(decoded, message);
+        assert!(decoded.is_response_to(&Message::query(0x1234, "x", RecordType::A)));
+    }
+
+    #[test]
+    fn round_trips_edns() {
+        let mut message = Message::query(1, "example.com", RecordType::Aaaa);
+        message.edns = Some(Edns {
+            udp_payload_size: 4096,
+            extended_rcode: 1,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![(10, vec![1, 2, 3, 4, 5, 6, 7, 8])],
+        });
+        let encoded = message.encode().unwrap();
+        // The OPT record is counted as an additional record
+        assert_eq!(&encoded[10..12], &[0, 1]);
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(decoded, message);
+        assert!(decoded.additionals.is_empty());
+    }
+
+    #[test]
+    fn rejects_multiple_opt_records() {
+        let mut encoded = Message::query(1, "example.com", RecordType::A)
+            .encode()
+            .unwrap();
+        let opt = encoded[encoded.len() - 11..].to_vec();
+        encoded.extend(opt);
+        encoded[11] = 2;
+        assert_eq!(Message::decode(&encoded), Err(Error::MultipleOpt));
+    }
+
+    #[test]
+    fn round_trips_truncation_flag() {
+        let mut message = response();
+        message.header.truncated = true;
+        let encoded = message.encode().unwrap();
+        assert_eq!(encoded[2] & 0x02, 0x02);
+        assert!(Message::decode(&encoded).unwrap().header.truncated);
+        message.header.truncated = false;
+        let encoded = message.encode().unwrap();
+        assert!(!Message::decode(&encoded).unwrap().header.truncated);
+    }
+
+    /// A response for www.example.com, with names compressed as a server would send it
+    fn compressed_response() -> Vec<u8> {
+        let mut message = vec![
+            0x00, 0x07, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+        ];
+        // Question at offset 12
+        message.extend(b"\x03www\x07example\x03com\x00");
+        message.extend([0x00, 0x01, 0x00, 0x01]);
+        // CNAME of www.example.com (offset 12) to mail + example.com (offset 16)
+        message.extend([0xC0, 0x0C, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C]);
+        message.extend([0x00, 0x07]);
+        let cname = message.len() as u8;
+        message.extend(b"\x04mail\xC0\x10");
+        // A record of the CNAME target
+        message.extend([0xC0, cname, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C]);
+        message.extend([0x00, 0x04, 192, 0, 2, 7]);
+        message
+    }
+
+    #[test]
+    fn decodes_compressed_names() {
+        let decoded = Message::decode(&compressed_response()).unwrap();
+        assert_eq!(decoded.questions[0].name, "www.example.com");
+        assert_eq!(decoded.answers[0].name, "www.example.com");
+        assert_eq!(
+            decoded.answers[0].data,
+            RecordData::Cname("mail.example.com".to_string())
+        );
+        assert_eq!(decoded.answers[1].name, "mail.example.com");
+        assert_eq!(
+            decoded.answers[1].data,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 7))
+        );
+    }
+
+    #[test]
+    fn rejects_pointers_that_do_not_point_backwards() {
+        let mut message = compressed_response();
+        // Point the first answer's name (at offset 33) at itself, and then forwards
+        message[34] = 33;
+        assert_eq!(Message::decode(&message), Err(Error::InvalidPointer));
+        message[34] = 200;
+        assert_eq!(Message::decode(&message), Err(Error::InvalidPointer));
+    }
+
+    #[test]
+    fn rejects_invalid_names_when_encoding() {
+        let long_label = "a".repeat(64);
+        let query = Message::query(1, &long_label, RecordType::A);
+        assert_eq!(query.encode(), Err(Error::LabelTooLong));
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        let query = Message::query(1, &long_name, RecordType::A);
+        assert_eq!(query.encode(), Err(Error::NameTooLong));
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        for message in [response().encode().unwrap(), compressed_response()] {
+            for length in 0..message.len() {
+                assert!(Message::decode(&message[..length]).is_err(), "{length}");
+            }
+        }
+    }
+
+    /// A small deterministic random number generator (xorshift), so failures can be reproduced
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, limit: usize) -> usize {
+            (self.next() % limit as u64) as usize
+        }
+    }
+
+    #[test]
+    fn decoding_random_bytes_does_not_panic() {
+        let mut random = Random(0x2545_F491_4F6C_DD1D);
+        for _ in 0..20_000 {
+            let length = random.below(600);
+            let bytes = (0..length).map(|_| random.next() as u8).collect::<Vec<_>>();
+            let _ = Message::decode(&bytes);
+        }
+    }
+
+    #[test]
+    fn decoding_corrupted_messages_does_not_panic() {
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        let messages = [response().encode().unwrap(), compressed_response()];
+        for _ in 0..20_000 {
+            let mut message = messages[random.below(messages.len())].clone();
+            for _ in 0..1 + random.below(4) {
+                let index = random.below(message.len());
+                message[index] = random.next() as u8;
+            }
+            message.truncate(1 + random.below(message.len()));
+            let _ = Message::decode(&message);
+        }
+    }
+}
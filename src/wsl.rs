@@ -1,14 +1,126 @@
-use std::io::Write;
+use std::io::{Read, Write};
 use std::os::windows::process::CommandExt;
 use std::process::{Command, Output, Stdio};
-use std::string::{FromUtf16Error, FromUtf8Error};
+use std::string::FromUtf8Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use windows::Win32::System::Threading::CREATE_NO_WINDOW;
 
-#[derive(Debug)]
+/// How often to check whether wsl.exe has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Limits on each call to wsl.exe
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Kill the call if it doesn't exit within this time
+    timeout: Duration,
+    /// Set to kill the call in progress, and fail any later calls
+    aborted: Arc<AtomicBool>,
+}
+
+impl Limits {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout: timeout.max(Duration::from_secs(1)),
+            aborted: Arc::default(),
+        }
+    }
+
+    /// The same limits, but which can be aborted separately
+    pub fn abortable(&self) -> Self {
+        Self::new(self.timeout)
+    }
+
+    /// Kills the call in progress (if any), and fails any later calls with these limits
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+}
+
+/// The state of a distribution, as shown by `wsl --list --verbose` in the display language
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DistributionState {
+    Running,
+    Stopped,
+    Installing,
+    Uninstalling,
+    Converting,
+    /// A state, or translation, that isn't recognised
+    Unknown(String),
+}
+
+impl DistributionState {
+    fn parse(state: &str) -> Self {
+        let state = state.to_lowercase();
+        let is = |words: &[&str]| words.contains(&state.as_str());
+        if is(&[
+            "running",
+            "wird ausgeführt",
+            "en cours d'exécution",
+            "en ejecución",
+            "in esecuzione",
+            "em execução",
+            "wordt uitgevoerd",
+            "uruchomiona",
+            "работает",
+            "çalışıyor",
+            "実行中",
+            "正在运行",
+            "執行中",
+            "실행 중",
+        ]) {
+            DistributionState::Running
+        } else if is(&[
+            "stopped",
+            "beendet",
+            "angehalten",
+            "arrêté",
+            "detenido",
+            "arrestato",
+            "interrotto",
+            "parado",
+            "gestopt",
+            "zatrzymana",
+            "остановлено",
+            "durduruldu",
+            "停止",
+            "已停止",
+            "중지됨",
+        ]) {
+            DistributionState::Stopped
+        } else if is(&[
+            "installing",
+            "wird installiert",
+            "installation",
+            "instalando",
+        ]) {
+            DistributionState::Installing
+        } else if is(&["uninstalling", "wird deinstalliert", "désinstallation"]) {
+            DistributionState::Uninstalling
+        } else if is(&[
+            "converting",
+            "wird konvertiert",
+            "conversion",
+            "convirtiendo",
+        ]) {
+            DistributionState::Converting
+        } else {
+            DistributionState::Unknown(state)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct WslDistribution {
     pub name: String,
-    pub status: String,
+    pub state: DistributionState,
     pub version: u32,
 }
 
@@ -34,14 +146,120 @@ pub enum Error {
         #[from]
         FromUtf8Error,
     ),
-    #[error("Unreadable utf16: {0}")]
-    UnreadableUtf16(
-        #[source]
-        #[from]
-        FromUtf16Error,
-    ),
     #[error("Couldn't parse output of wsl list command")]
     UnexpectedListOutput,
+    #[error("Timed out running: {command}")]
+    Timeout { command: String },
+    #[error("Aborted running: {command}")]
+    Aborted { command: String },
+}
+
+impl Error {
+    /// A short single line description of the error, suitable for a notification
+    pub fn summary(&self) -> String {
+        match self {
+            Error::BadStatus {
+                code,
+                stderr_16,
+                stderr_8,
+                ..
+            } => {
+                // Errors from wsl.exe itself are UTF-16, whereas errors from within the
+                // distribution are UTF-8; a UTF-8 string containing nulls is really UTF-16
+                let stderr = stderr_8
+                    .as_deref()
+                    .filter(|s| !s.contains('\0'))
+                    .or(stderr_16.as_deref())
+                    .and_then(|s| s.lines().map(str::trim).find(|l| !l.is_empty()));
+                match stderr {
+                    Some(line) => format!("exit status {code}: {line}"),
+                    None => format!("exit status {code}"),
+                }
+            }
+            Error::Io(e) => format!("unable to run wsl.exe: {e}"),
+            e => e.to_string(),
+        }
+    }
+
+    /// Whether `wsl --list` failed because there are no distributions installed
+    fn is_no_distributions(&self) -> bool {
+        match self {
+            Error::BadStatus {
+                stdout_16,
+                stderr_16,
+                ..
+            } => [stdout_16, stderr_16].into_iter().flatten().any(|s| {
+                // Unlike the message the error code isn't translated, but older versions of WSL
+                // only print the (English) message
+                error_codes(s)
+                    .iter()
+                    .any(|code| code == "WSL_E_DEFAULT_DISTRO_NOT_FOUND")
+                    || s.contains("has no installed distributions")
+            }),
+            _ => false,
+        }
+    }
+
+    /// Whether the error may go away by itself, e.g. while the WSL service or VM is starting
+    pub fn is_transient(&self) -> bool {
+        match self {
+            // Errors from wsl.exe itself are UTF-16 and include an error code, whereas errors from
+            // a command within the distribution (such as chattr not being supported) don't
+            Error::BadStatus {
+                stdout_16,
+                stderr_16,
+                ..
+            } => [stdout_16, stderr_16]
+                .into_iter()
+                .flatten()
+                .flat_map(|s| error_codes(s))
+                .any(|code| is_transient_code(&code)),
+            Error::Timeout { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Error codes from wsl.exe that are expected to go away by themselves, as the WSL service or VM
+/// is (re)starting
+const TRANSIENT_CODES: &[(&str, u32)] = &[
+    ("RPC_S_SERVER_UNAVAILABLE", 0x8007_06BA),
+    ("RPC_S_CALL_FAILED", 0x8007_06BE),
+    ("ERROR_SERVICE_NOT_ACTIVE", 0x8007_0426),
+    ("ERROR_TIMEOUT", 0x8007_05B4),
+    ("ERROR_PIPE_BUSY", 0x8007_00E7),
+    ("ERROR_BROKEN_PIPE", 0x8007_006D),
+    ("HCS_E_SERVICE_NOT_AVAILABLE", 0x8037_0114),
+    ("HCS_E_CONNECTION_TIMEOUT", 0x8037_0109),
+];
+
+/// The error codes in the output of wsl.exe, which are either the last part of a code such as
+/// "Wsl/Service/CreateInstance/HCS_E_SERVICE_NOT_AVAILABLE", or a HRESULT such as "0x800706ba"
+/// (from older versions). Unlike the messages, these aren't translated
+fn error_codes(output: &str) -> Vec<String> {
+    output
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_ascii_alphanumeric() && c != '_'))
+        .filter_map(|word| {
+            if word.starts_with("Wsl/") {
+                word.rsplit('/').next()
+            } else if word.starts_with("0x") {
+                Some(word)
+            } else {
+                None
+            }
+        })
+        .map(String::from)
+        .collect()
+}
+
+fn is_transient_code(code: &str) -> bool {
+    let hresult = code
+        .strip_prefix("0x")
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+    TRANSIENT_CODES
+        .iter()
+        .any(|(name, value)| code == *name || hresult == Some(*value))
 }
 
 fn to_u16(original: &[u8]) -> Vec<u16> {
@@ -51,35 +269,221 @@ fn to_u16(original: &[u8]) -> Vec<u16> {
         .collect()
 }
 
-pub fn get_distributions() -> Result<Vec<WslDistribution>, Error> {
-    let output = Command::new("wsl.exe")
-        .creation_flags(CREATE_NO_WINDOW.0)
-        .arg("--list")
-        .arg("--verbose")
-        .output()?;
+fn wsl() -> Command {
+    let mut command = Command::new("wsl.exe");
+    command.creation_flags(CREATE_NO_WINDOW.0);
+    command
+}
+
+/// Runs the command, writing the input to its stdin, and killing it if it doesn't exit within
+/// the timeout or is aborted
+fn run(limits: &Limits, mut command: Command, input: Option<&str>) -> Result<Output, Error> {
+    let command_line = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|a| a.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if limits.is_aborted() {
+        return Err(Error::Aborted {
+            command: command_line,
+        });
+    }
+    let mut child = command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Use separate threads to write and read, so that a hung process can't block this one
+    let writer = match (child.stdin.take(), input) {
+        (Some(mut stdin), Some(input)) => {
+            let input = input.to_string();
+            Some(spawn(move || stdin.write_all(input.as_bytes())))
+        }
+        _ => None,
+    };
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let deadline = Instant::now() + limits.timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            log::warn!(
+                "Killing `{command_line}` as it didn't exit within {:?}",
+                limits.timeout
+            );
+            child.kill().ok();
+            child.wait().ok();
+            return Err(Error::Timeout {
+                command: command_line,
+            });
+        }
+        if limits.is_aborted() {
+            log::warn!("Killing `{command_line}` as it was aborted");
+            child.kill().ok();
+            child.wait().ok();
+            return Err(Error::Aborted {
+                command: command_line,
+            });
+        }
+        sleep(POLL_INTERVAL);
+    };
+    if let Some(writer) = writer {
+        // If the command failed, it probably stopped reading early, so its status is the more
+        // useful error
+        let written = writer.join().unwrap();
+        if status.success() {
+            written?;
+        }
+    }
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap()?,
+        stderr: stderr.join().unwrap()?,
+    })
+}
+
+fn read_in_background(
+    pipe: Option<impl Read + Send + 'static>,
+) -> JoinHandle<std::io::Result<Vec<u8>>> {
+    spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buffer)?;
+        }
+        Ok(buffer)
+    })
+}
+
+/// Returns the raw output of `wsl --list --verbose`
+pub fn list_verbose(limits: &Limits) -> Result<String, Error> {
+    let mut command = wsl();
+    command.arg("--list").arg("--verbose");
+    let output = run(limits, command, None)?;
     check_wsl_output(&output)?;
-    let stdout = String::from_utf16(&to_u16(&output.stdout))?;
-    let dist = stdout
+    Ok(String::from_utf16_lossy(&to_u16(&output.stdout)))
+}
+
+pub fn get_distributions(limits: &Limits) -> Result<Vec<WslDistribution>, Error> {
+    match list_verbose(limits) {
+        Ok(stdout) => parse_list(&stdout),
+        Err(e) if e.is_no_distributions() => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses the output of `wsl --list --verbose`. The columns are found from the positions of the
+/// header, as the header and states are translated and may contain spaces:
+///
+/// ```text
+///   NAME            STATE           VERSION
+/// * Ubuntu          Running         2
+///   Debian          Stopped         1
+/// ```
+fn parse_list(output: &str) -> Result<Vec<WslDistribution>, Error> {
+    // wsl.exe may write a byte order mark, or stray nulls
+    let mut lines = output
         .lines()
+        .map(|line| {
+            line.chars()
+                .filter(|&c| !c.is_control() && c != '\u{feff}' && c != '\u{fffd}')
+                .collect::<Vec<_>>()
+        })
+        .filter(|line| line.iter().any(|c| !c.is_whitespace()));
+    let Some(header) = lines.next() else {
+        return Ok(vec![]);
+    };
+    let rows = lines.collect::<Vec<_>>();
+    if word_starts(&header).len() < 3 {
+        return Err(Error::UnexpectedListOutput);
+    }
+    let columns = columns(&header, &rows);
+    let mut distributions = vec![];
+    for row in rows {
+        match parse_list_row(&row, columns) {
+            Some(distribution) => distributions.push(distribution),
+            None => log::warn!(
+                "Ignoring unexpected line from wsl --list: {:?}",
+                row.iter().collect::<String>()
+            ),
+        }
+    }
+    Ok(distributions)
+}
+
+/// The position of each word in the line
+fn word_starts(line: &[char]) -> Vec<usize> {
+    (0..line.len())
+        .filter(|&i| !line[i].is_whitespace() && (i == 0 || line[i - 1].is_whitespace()))
+        .collect()
+}
+
+/// The positions of the state and version columns. A translated header may contain spaces, so
+/// these are the first and last words of the header (after the name) that every row also has a
+/// word at, or `None` if the rows don't line up with the header
+fn columns(header: &[char], rows: &[Vec<char>]) -> Option<(usize, usize)> {
+    let columns = word_starts(header)
+        .into_iter()
         .skip(1)
-        .map(|mut line| {
-            if line.starts_with('*') {
-                line = &line[1..];
-            }
-            let components = line.split_whitespace().collect::<Vec<_>>();
-            if components.len() != 3 {
-                return Err(Error::UnexpectedListOutput);
-            }
-            Ok(WslDistribution {
-                name: components[0].to_string(),
-                status: components[1].to_string(),
-                version: components[2]
-                    .parse()
-                    .map_err(|_| Error::UnexpectedListOutput)?,
-            })
+        .filter(|&i| {
+            rows.iter()
+                .all(|row| row.len() > i && row[i - 1].is_whitespace() && !row[i].is_whitespace())
         })
-        .collect::<Result<Vec<_>, _>>()?;
-    Ok(dist)
+        .collect::<Vec<_>>();
+    match columns[..] {
+        [state, .., version] => Some((state, version)),
+        _ => None,
+    }
+}
+
+/// Parses a row using the column positions from the header, or the whitespace between words if
+/// the rows don't line up with the header
+fn parse_list_row(line: &[char], columns: Option<(usize, usize)>) -> Option<WslDistribution> {
+    let column = |range: std::ops::Range<usize>| {
+        let text = line[range].iter().collect::<String>();
+        // The default distribution is marked with an asterisk
+        text.trim().trim_start_matches('*').trim().to_string()
+    };
+    let (name, state, version) = if let Some((state, version)) = columns {
+        (
+            column(0..state),
+            column(state..version),
+            column(version..line.len()),
+        )
+    } else {
+        let line = line.iter().collect::<String>();
+        let mut words = line
+            .trim_start()
+            .trim_start_matches('*')
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        let version = words.pop()?.to_string();
+        if words.len() < 2 {
+            return None;
+        }
+        let name = words.remove(0).to_string();
+        (name, words.join(" "), version)
+    };
+    let version = version.parse().ok()?;
+    if name.is_empty() || state.is_empty() {
+        return None;
+    }
+    let state = DistributionState::parse(&state);
+    if let DistributionState::Unknown(state) = &state {
+        log::debug!("Unrecognised state for {name}: {state}");
+    }
+    Some(WslDistribution {
+        name,
+        state,
+        version,
+    })
 }
 
 fn check_wsl_output(output: &Output) -> Result<(), Error> {
@@ -102,70 +506,302 @@ fn check_wsl_output(output: &Output) -> Result<(), Error> {
 }
 
 impl WslDistribution {
-    pub fn read_file(&self, path: &str) -> Result<String, Error> {
-        let output = Command::new("wsl.exe")
-            .creation_flags(CREATE_NO_WINDOW.0)
-            .arg("--distribution")
-            .arg(&self.name)
-            .arg("--user")
-            .arg("root")
-            .arg("cat")
-            .arg(path)
-            .output()?;
+    /// Runs a command as root within the distribution, returning its output
+    pub fn exec(&self, limits: &Limits, command: &[&str]) -> Result<String, Error> {
+        let output = run(limits, self.command(command), None)?;
         check_wsl_output(&output)?;
         Ok(String::from_utf8(output.stdout)?)
     }
 
-    pub fn set_read_only(&self, path: &str, read_only: bool) -> Result<(), Error> {
-        let arg = if read_only { "+i" } else { "-i" };
-        let output = Command::new("wsl.exe")
-            .creation_flags(CREATE_NO_WINDOW.0)
-            .arg("--distribution")
+    fn command(&self, command: &[&str]) -> Command {
+        let mut wsl = wsl();
+        wsl.arg("--distribution")
             .arg(&self.name)
             .arg("--user")
             .arg("root")
-            .arg("chattr")
-            .arg(arg)
-            .arg(path)
-            .output()?;
-        check_wsl_output(&output)?;
-        Ok(())
+            .args(command);
+        wsl
     }
 
-    pub fn write_file(&self, path: &str, contents: &str) -> Result<(), Error> {
-        let mut p = Command::new("wsl.exe")
-            .creation_flags(CREATE_NO_WINDOW.0)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .arg("--distribution")
-            .arg(&self.name)
-            .arg("--user")
-            .arg("root")
-            .arg("tee")
-            .arg(path)
-            .spawn()?;
+    pub fn read_file(&self, limits: &Limits, path: &str) -> Result<String, Error> {
+        self.exec(limits, &["cat", path])
+    }
 
-        let mut child_stdin = p.stdin.take().unwrap();
-        child_stdin.write_all(contents.as_bytes())?;
-        drop(child_stdin);
+    pub fn set_read_only(&self, limits: &Limits, path: &str, read_only: bool) -> Result<(), Error> {
+        let arg = if read_only { "+i" } else { "-i" };
+        self.exec(limits, &["chattr", arg, path])?;
+        Ok(())
+    }
 
-        let output = p.wait_with_output()?;
+    /// Runs a command as root within the distribution, writing the input to its stdin
+    pub fn exec_with_input(
+        &self,
+        limits: &Limits,
+        command: &[&str],
+        input: &str,
+    ) -> Result<String, Error> {
+        let output = run(limits, self.command(command), Some(input))?;
         check_wsl_output(&output)?;
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    pub fn write_file(&self, limits: &Limits, path: &str, contents: &str) -> Result<(), Error> {
+        self.exec_with_input(limits, &["tee", path], contents)?;
         Ok(())
     }
 
-    pub fn terminate(&self) -> Result<(), Error> {
-        let output = Command::new("wsl.exe")
-            .creation_flags(CREATE_NO_WINDOW.0)
-            .arg("--terminate")
-            .arg(&self.name)
-            .output()?;
+    /// Runs a shell script as root within the distribution, returning its output
+    pub fn run_script(&self, limits: &Limits, script: &str) -> Result<String, Error> {
+        self.exec_with_input(limits, &["--exec", "sh", "-s"], script)
+    }
+
+    pub fn terminate(&self, limits: &Limits) -> Result<(), Error> {
+        let mut command = wsl();
+        command.arg("--terminate").arg(&self.name);
+        let output = run(limits, command, None)?;
         check_wsl_output(&output)?;
         Ok(())
     }
 
     pub fn was_stopped(&self) -> bool {
-        self.status == "Stopped"
+        self.state == DistributionState::Stopped
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == DistributionState::Running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(output: &str) -> Vec<(String, DistributionState, u32)> {
+        parse_list(output)
+            .unwrap()
+            .into_iter()
+            .map(|d| (d.name, d.state, d.version))
+            .collect()
+    }
+
+    fn distribution(
+        name: &str,
+        state: DistributionState,
+        version: u32,
+    ) -> (String, DistributionState, u32) {
+        (name.to_string(), state, version)
+    }
+
+    #[test]
+    fn parses_english_output() {
+        let output = "  NAME                   STATE           VERSION
+* Ubuntu                 Running         2
+  docker-desktop-data    Stopped         2
+  Legacy                 Stopped         1
+  Ubuntu-Preview         Installing      2
+";
+        assert_eq!(
+            parse(output),
+            [
+                distribution("Ubuntu", DistributionState::Running, 2),
+                distribution("docker-desktop-data", DistributionState::Stopped, 2),
+                distribution("Legacy", DistributionState::Stopped, 1),
+                distribution("Ubuntu-Preview", DistributionState::Installing, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_german_output() {
+        let output = "  NAME      STATUS             VERSION
+* Ubuntu    Wird ausgeführt    2
+  Debian    Beendet            2
+";
+        assert_eq!(
+            parse(output),
+            [
+                distribution("Ubuntu", DistributionState::Running, 2),
+                distribution("Debian", DistributionState::Stopped, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_french_output() {
+        let output = "  NOM       ÉTAT                    VERSION
+* Ubuntu    En cours d'exécution    2
+  Debian    Arrêté                  2
+  Alpine    État inconnu            2
+";
+        assert_eq!(
+            parse(output),
+            [
+                distribution("Ubuntu", DistributionState::Running, 2),
+                distribution("Debian", DistributionState::Stopped, 2),
+                distribution(
+                    "Alpine",
+                    DistributionState::Unknown("état inconnu".to_string()),
+                    2
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_translated_headers_containing_spaces() {
+        let output = "  NOM DE LA DISTRIBUTION    ÉTAT ACTUEL             VERSION WSL
+* Ubuntu                    En cours d'exécution    2
+  Debian                    Arrêté                  1
+";
+        assert_eq!(
+            parse(output),
+            [
+                distribution("Ubuntu", DistributionState::Running, 2),
+                distribution("Debian", DistributionState::Stopped, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_byte_order_marks_and_nulls() {
+        let output = "\u{feff}  NAME      STATE           VERSION\r\n\0\
+                      * Ubuntu    Running         2\r\n\0\
+                      \0\r\n\
+                      \u{feff}  Debian    Stopped         2\r\n\0";
+        assert_eq!(
+            parse(output),
+            [
+                distribution("Ubuntu", DistributionState::Running, 2),
+                distribution("Debian", DistributionState::Stopped, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_names_containing_spaces() {
+        let output = "  NAME                STATE           VERSION
+* Ubuntu 22.04 LTS    Running         2
+  Debian              Stopped         2
+";
+        assert_eq!(
+            parse(output),
+            [
+                distribution("Ubuntu 22.04 LTS", DistributionState::Running, 2),
+                distribution("Debian", DistributionState::Stopped, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_rows_that_do_not_line_up_with_the_header() {
+        let output = "NAME STATE VERSION
+* Ubuntu    Wird ausgeführt    2
+  Debian Stopped 1
+";
+        assert_eq!(
+            parse(output),
+            [
+                distribution("Ubuntu", DistributionState::Running, 2),
+                distribution("Debian", DistributionState::Stopped, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_unexpected_rows() {
+        let output = "  NAME      STATE           VERSION
+* Ubuntu    Running         2
+  Debian    Stopped         ?
+  Broken
+";
+        assert_eq!(
+            parse(output),
+            [distribution("Ubuntu", DistributionState::Running, 2)]
+        );
+    }
+
+    #[test]
+    fn rejects_unexpected_headers() {
+        assert!(matches!(
+            parse_list("Access denied\n"),
+            Err(Error::UnexpectedListOutput)
+        ));
+        assert!(parse("").is_empty());
+        assert!(parse("\u{feff}\r\n\0").is_empty());
+    }
+
+    fn wsl_error(stdout: &str, stderr_8: &str) -> Error {
+        Error::BadStatus {
+            code: -1,
+            stderr_16: Some(String::new()),
+            stderr_8: Some(stderr_8.to_string()),
+            stdout_16: Some(stdout.to_string()),
+            stdout_8: None,
+        }
+    }
+
+    #[test]
+    fn finds_error_codes() {
+        let output = "The service failed to start.\r\n\
+                      Error code: Wsl/Service/CreateInstance/CreateVm/HCS_E_SERVICE_NOT_AVAILABLE\r\n";
+        assert_eq!(error_codes(output), ["HCS_E_SERVICE_NOT_AVAILABLE"]);
+        assert_eq!(error_codes("Error: 0x800706ba."), ["0x800706ba"]);
+        assert!(error_codes("chattr: Operation not supported").is_empty());
+    }
+
+    #[test]
+    fn recognises_transient_errors_by_the_error_code() {
+        let transient = [
+            "Error code: Wsl/Service/CreateInstance/CreateVm/HCS_E_SERVICE_NOT_AVAILABLE",
+            "Error code: Wsl/Service/RPC_S_SERVER_UNAVAILABLE",
+            "Error code: Wsl/Service/0x800706be",
+            "The remote procedure call failed.\r\nError: 0x800706BE",
+        ];
+        for output in transient {
+            assert!(wsl_error(output, "").is_transient(), "{output}");
+        }
+        let permanent = [
+            "Error code: Wsl/Service/E_ACCESSDENIED",
+            "Error code: Wsl/WSL_E_DISTRO_NOT_FOUND",
+            "Error: 0x80070005",
+            "",
+        ];
+        for output in permanent {
+            assert!(!wsl_error(output, "").is_transient(), "{output}");
+        }
+        // Errors from within the distribution are UTF-8
+        let chattr = wsl_error("", "chattr: Operation not supported while setting flags\n");
+        assert!(!chattr.is_transient());
+        let timeout = Error::Timeout {
+            command: "wsl.exe --list".to_string(),
+        };
+        assert!(timeout.is_transient());
+    }
+
+    fn list_error(stdout: &str) -> Error {
+        Error::BadStatus {
+            code: -1,
+            stderr_16: Some(String::new()),
+            stderr_8: Some(String::new()),
+            stdout_16: Some(stdout.to_string()),
+            stdout_8: None,
+        }
+    }
+
+    #[test]
+    fn recognises_no_distributions() {
+        let english = "Windows Subsystem for Linux has no installed distributions.\r\n\
+                       Error code: Wsl/WSL_E_DEFAULT_DISTRO_NOT_FOUND\r\n";
+        let german =
+            "Für das Windows-Subsystem für Linux sind keine Distributionen installiert.\r\n\
+                      Fehlercode: Wsl/WSL_E_DEFAULT_DISTRO_NOT_FOUND\r\n";
+        assert!(list_error(english).is_no_distributions());
+        assert!(list_error(german).is_no_distributions());
+        // Older versions don't print an error code
+        assert!(
+            list_error("Windows Subsystem for Linux has no installed distributions.")
+                .is_no_distributions()
+        );
+        assert!(!list_error("Error code: Wsl/Service/E_UNEXPECTED").is_no_distributions());
     }
 }
@@ -0,0 +1,517 @@
+use crate::config::WslConfigSetting;
+use serde::Serialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::PathBuf;
+use thiserror::Error;
+
+const BOM: char = '\u{feff}';
+
+/// Keys whose values are paths, or passed to the Linux kernel, so are compared exactly
+const EXACT_KEYS: &[&str] = &["kernel", "kernelModules", "kernelCommandLine", "swapFile"];
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unable to read {}: {}", .0.display(), .1)]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Unable to write {}: {}", .0.display(), .1)]
+    Write(PathBuf, #[source] std::io::Error),
+}
+
+/// How WSL2 distributions are connected to the network
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkingMode {
+    /// Behind a NAT on the vEthernet (WSL) adapter
+    Nat,
+    /// Sharing the Windows network interfaces, including loopback
+    Mirrored,
+    Bridged,
+    VirtioProxy,
+    None,
+}
+
+impl Default for NetworkingMode {
+    fn default() -> Self {
+        Self::Nat
+    }
+}
+
+impl NetworkingMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "nat" => Some(NetworkingMode::Nat),
+            "mirrored" => Some(NetworkingMode::Mirrored),
+            "bridged" => Some(NetworkingMode::Bridged),
+            "virtioproxy" => Some(NetworkingMode::VirtioProxy),
+            "none" => Some(NetworkingMode::None),
+            _ => None,
+        }
+    }
+}
+
+/// The effective global WSL2 settings from %USERPROFILE%\.wslconfig that affect the agent,
+/// settings that aren't set are `None` since their defaults depend on the version of WSL
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct WslConfig {
+    pub networking_mode: NetworkingMode,
+    /// WSL answers DNS queries itself, by forwarding them to Windows
+    pub dns_tunneling: Option<bool>,
+    /// With NAT, WSL points resolv.conf at a DNS proxy on the host
+    pub dns_proxy: Option<bool>,
+    /// Milliseconds before an idle VM is shut down
+    pub vm_idle_timeout: Option<i64>,
+    #[serde(skip)]
+    warnings: Vec<String>,
+}
+
+impl WslConfig {
+    pub fn path() -> PathBuf {
+        dirs::home_dir().unwrap().join(".wslconfig")
+    }
+
+    /// Reads the settings, using the defaults if the file doesn't exist
+    pub fn load() -> Result<Self, Error> {
+        Ok(Self::from_file(&WslConfigFile::load()?))
+    }
+
+    /// The settings in the file, any invalid values are returned in `warnings()` rather than
+    /// logged, since the file is read on every run
+    pub fn from_file(file: &WslConfigFile) -> Self {
+        let mut warnings = vec![];
+        let mut bool = |key: &str| {
+            let value = file.get("wsl2", key)?;
+            parse_bool(value).or_else(|| {
+                warnings.push(format!("Invalid {key} in .wslconfig: {value}"));
+                None
+            })
+        };
+        let dns_tunneling = bool("dnsTunneling");
+        let dns_proxy = bool("dnsProxy");
+        let networking_mode = match file.get("wsl2", "networkingMode") {
+            Some(value) => NetworkingMode::parse(value).unwrap_or_else(|| {
+                warnings.push(format!("Unknown networkingMode in .wslconfig: {value}"));
+                NetworkingMode::default()
+            }),
+            None => NetworkingMode::default(),
+        };
+        Self {
+            networking_mode,
+            dns_tunneling,
+            dns_proxy,
+            vm_idle_timeout: file
+                .get("wsl2", "vmIdleTimeout")
+                .and_then(|v| v.parse().ok()),
+            warnings,
+        }
+    }
+
+    /// Invalid values found in the file, which were ignored
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Only when explicitly enabled, since the default depends on the version of WSL
+    pub fn dns_tunneling(&self) -> bool {
+        self.dns_tunneling == Some(true)
+    }
+}
+
+/// WSL reads booleans ignoring case
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Line {
+    Section {
+        raw: String,
+        name: String,
+    },
+    Entry {
+        raw: String,
+        key: String,
+        /// Position of the value within the raw line
+        value: Range<usize>,
+    },
+    /// Comments, blank lines, and anything that can't be parsed
+    Other(String),
+}
+
+impl Line {
+    fn parse(raw: &str) -> Self {
+        let trimmed = raw.trim();
+        // A section header may be followed by a comment
+        if let Some((name, rest)) = trimmed
+            .strip_prefix('[')
+            .and_then(|header| header.split_once(']'))
+        {
+            let rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with('#') || rest.starts_with(';') {
+                return Line::Section {
+                    raw: raw.to_string(),
+                    name: name.trim().to_string(),
+                };
+            }
+        }
+        if trimmed.starts_with('#') || trimmed.starts_with(';') {
+            return Line::Other(raw.to_string());
+        }
+        match raw.split_once('=') {
+            Some((key, rest)) if !key.trim().is_empty() => {
+                let start = key.len() + 1 + (rest.len() - rest.trim_start().len());
+                let end = (key.len() + 1 + rest.trim_end().len()).max(start);
+                Line::Entry {
+                    raw: raw.to_string(),
+                    key: key.trim().to_string(),
+                    value: start..end,
+                }
+            }
+            _ => Line::Other(raw.to_string()),
+        }
+    }
+
+    fn raw(&self) -> &str {
+        match self {
+            Line::Section { raw, .. } | Line::Entry { raw, .. } | Line::Other(raw) => raw,
+        }
+    }
+}
+
+/// The contents of a .wslconfig file, which can be edited without losing any comments or
+/// formatting. Section names and keys are matched ignoring case, the same as WSL
+#[derive(Debug, Clone, Default)]
+pub struct WslConfigFile {
+    lines: Vec<Line>,
+    bom: bool,
+    crlf: bool,
+    trailing_newline: bool,
+}
+
+impl WslConfigFile {
+    /// Reads the file, or returns an empty file if it doesn't exist
+    pub fn load() -> Result<Self, Error> {
+        let path = WslConfig::path();
+        match fs::read_to_string(&path) {
+            Ok(contents) => Ok(Self::parse(&contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::Read(path, e)),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = WslConfig::path();
+        fs::write(&path, self.to_string()).map_err(|e| Error::Write(path, e))
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        // Notepad may have saved the file with a byte order mark
+        let bom = contents.starts_with(BOM);
+        let contents = contents.trim_start_matches(BOM);
+        let crlf = contents.contains("\r\n");
+        let trailing_newline = contents.ends_with('\n');
+        let contents = contents.strip_suffix('\n').unwrap_or(contents);
+        let lines = if contents.is_empty() && !trailing_newline {
+            vec![]
+        } else {
+            contents
+                .split('\n')
+                .map(|line| Line::parse(line.strip_suffix('\r').unwrap_or(line)))
+                .collect()
+        };
+        Self {
+            lines,
+            bom,
+            crlf,
+            trailing_newline,
+        }
+    }
+
+    /// The line index of each entry in the section, and the index of the section header
+    fn section(&self, section: &str) -> (Option<usize>, Vec<usize>) {
+        let mut header = None;
+        let mut entries = vec![];
+        // Entries before the first section header are in the "" section
+        let mut current = String::new();
+        for (index, line) in self.lines.iter().enumerate() {
+            match line {
+                Line::Section { name, .. } => {
+                    current = name.clone();
+                    if header.is_none() && current.eq_ignore_ascii_case(section) {
+                        header = Some(index);
+                    }
+                }
+                Line::Entry { .. } if current.eq_ignore_ascii_case(section) => entries.push(index),
+                _ => {}
+            }
+        }
+        (header, entries)
+    }
+
+    /// The value of the last matching entry, as WSL uses the last value if a key is repeated
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        let (_, entries) = self.section(section);
+        entries
+            .iter()
+            .rev()
+            .find_map(|&index| match &self.lines[index] {
+                Line::Entry { raw, key: k, value } if k.eq_ignore_ascii_case(key) => {
+                    Some(&raw[value.clone()])
+                }
+                _ => None,
+            })
+    }
+
+    /// Whether the entry has the value, which is compared ignoring case (e.g. "True" and "true",
+    /// or "NAT" and "nat" are the same) the same as WSL, other than for paths
+    pub fn has_value(&self, section: &str, key: &str, value: &str) -> bool {
+        let exact = EXACT_KEYS.iter().any(|k| k.eq_ignore_ascii_case(key));
+        self.get(section, key).is_some_and(|current| {
+            if exact {
+                current == value
+            } else {
+                current.eq_ignore_ascii_case(value)
+            }
+        })
+    }
+
+    /// Sets the value, replacing the existing value in place, otherwise adding it to the end of
+    /// the section (or a new section). Returns whether the file changed
+    pub fn set(&mut self, section: &str, key: &str, value: &str) -> bool {
+        if self.has_value(section, key, value) {
+            return false;
+        }
+        let (header, entries) = self.section(section);
+        let existing = entries.iter().rev().copied().find(|&index| {
+            matches!(&self.lines[index], Line::Entry { key: k, .. } if k.eq_ignore_ascii_case(key))
+        });
+        if let Some(index) = existing {
+            if let Line::Entry {
+                raw, value: range, ..
+            } = &self.lines[index]
+            {
+                let raw = format!("{}{value}{}", &raw[..range.start], &raw[range.end..]);
+                self.lines[index] = Line::parse(&raw);
+            }
+            return true;
+        }
+        let entry = Line::parse(&format!("{key}={value}"));
+        match (header, entries.last()) {
+            (_, Some(&last)) => self.lines.insert(last + 1, entry),
+            (Some(header), None) => self.lines.insert(header + 1, entry),
+            (None, None) => {
+                if matches!(self.lines.last(), Some(l) if !l.raw().trim().is_empty()) {
+                    self.lines.push(Line::Other(String::new()));
+                }
+                self.lines.push(Line::parse(&format!("[{section}]")));
+                self.lines.push(entry);
+                self.trailing_newline = true;
+            }
+        }
+        true
+    }
+}
+
+impl std::fmt::Display for WslConfigFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let newline = if self.crlf { "\r\n" } else { "\n" };
+        if self.bom {
+            write!(f, "{BOM}")?;
+        }
+        let lines = self.lines.iter().map(Line::raw).collect::<Vec<_>>();
+        write!(f, "{}", lines.join(newline))?;
+        if self.trailing_newline && !lines.is_empty() {
+            write!(f, "{newline}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Updates .wslconfig so that it has the desired values, returning the values that were changed.
+/// WSL only reads the file when it starts, so the changes need a `wsl --shutdown`
+pub fn reconcile(setting: &WslConfigSetting) -> Result<Vec<String>, Error> {
+    let mut file = WslConfigFile::load()?;
+    let changes = desired_values(setting)
+        .filter(|(section, key, value)| file.set(section, key, value))
+        .map(|(section, key, value)| format!("{section}.{key}={value}"))
+        .collect::<Vec<_>>();
+    if !changes.is_empty() {
+        file.save()?;
+    }
+    Ok(changes)
+}
+
+/// Each desired section, key, and value, formatted as they would be in .wslconfig
+pub fn desired_values(
+    setting: &WslConfigSetting,
+) -> impl Iterator<Item = (&str, &str, String)> + '_ {
+    setting.desired.iter().flat_map(|(section, values)| {
+        values.iter().map(move |(key, value)| {
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            (section.as_str(), key.as_str(), value)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+# Settings for WSL2
+[wsl2]
+memory = 4GB   # comment
+networkingMode=NAT
+
+[experimental]
+autoMemoryReclaim = gradual
+";
+
+    fn set(contents: &str, section: &str, key: &str, value: &str) -> String {
+        let mut file = WslConfigFile::parse(contents);
+        assert!(file.set(section, key, value));
+        file.to_string()
+    }
+
+    #[test]
+    fn round_trips_unchanged() {
+        let crlf = CONFIG.replace('\n', "\r\n");
+        let bom = format!("{BOM}{CONFIG}");
+        let no_newline = CONFIG.trim_end();
+        for contents in [
+            CONFIG,
+            &crlf,
+            &bom,
+            no_newline,
+            "",
+            "\n",
+            "key=value",
+            "[wsl2",
+        ] {
+            assert_eq!(WslConfigFile::parse(contents).to_string(), contents);
+        }
+    }
+
+    #[test]
+    fn gets_values_ignoring_case() {
+        let file = WslConfigFile::parse(CONFIG);
+        assert_eq!(file.get("WSL2", "NetworkingMode"), Some("NAT"));
+        assert_eq!(file.get("wsl2", "memory"), Some("4GB   # comment"));
+        assert_eq!(file.get("experimental", "networkingMode"), None);
+        assert_eq!(file.get("wsl2", "swap"), None);
+    }
+
+    #[test]
+    fn gets_the_last_repeated_value() {
+        let file = WslConfigFile::parse("[wsl2]\ndnsProxy=true\n[WSL2]\ndnsProxy = false\n");
+        assert_eq!(file.get("wsl2", "dnsProxy"), Some("false"));
+    }
+
+    #[test]
+    fn replaces_values_in_place() {
+        assert_eq!(
+            set(CONFIG, "wsl2", "networkingMode", "mirrored"),
+            CONFIG.replace("networkingMode=NAT", "networkingMode=mirrored")
+        );
+        assert_eq!(
+            set(
+                &CONFIG.replace('\n', "\r\n"),
+                "Experimental",
+                "AUTOMEMORYRECLAIM",
+                "dropcache"
+            ),
+            CONFIG
+                .replace("= gradual", "= dropcache")
+                .replace('\n', "\r\n")
+        );
+    }
+
+    #[test]
+    fn adds_values_to_the_end_of_the_section() {
+        assert_eq!(
+            set(CONFIG, "wsl2", "dnsTunneling", "true"),
+            CONFIG.replace(
+                "networkingMode=NAT\n",
+                "networkingMode=NAT\ndnsTunneling=true\n"
+            )
+        );
+        assert_eq!(
+            set("[wsl2]\n", "wsl2", "dnsTunneling", "true"),
+            "[wsl2]\ndnsTunneling=true\n"
+        );
+    }
+
+    #[test]
+    fn adds_new_sections() {
+        assert_eq!(
+            set(CONFIG, "network", "hostname", "wsl"),
+            format!("{CONFIG}\n[network]\nhostname=wsl\n")
+        );
+        assert_eq!(
+            set(&format!("{BOM}"), "wsl2", "dnsTunneling", "true"),
+            format!("{BOM}[wsl2]\ndnsTunneling=true\n")
+        );
+        assert_eq!(
+            set("", "wsl2", "dnsTunneling", "true"),
+            "[wsl2]\ndnsTunneling=true\n"
+        );
+    }
+
+    #[test]
+    fn compares_values_ignoring_case() {
+        let contents =
+            "[wsl2]\ndnsTunneling=True\nnetworkingMode=NAT\nkernel=C:\\Kernel\\bzImage\n";
+        let mut file = WslConfigFile::parse(contents);
+        assert!(file.has_value("wsl2", "dnsTunneling", "true"));
+        assert!(!file.has_value("wsl2", "dnsTunneling", "false"));
+        assert!(file.has_value("wsl2", "networkingMode", "nat"));
+        assert!(!file.set("wsl2", "dnsTunneling", "true"));
+        assert!(!file.set("wsl2", "networkingMode", "nat"));
+        assert_eq!(file.to_string(), contents);
+        // Paths are compared exactly
+        assert!(file.has_value("wsl2", "kernel", "C:\\Kernel\\bzImage"));
+        assert!(!file.has_value("wsl2", "kernel", "c:\\kernel\\bzimage"));
+    }
+
+    #[test]
+    fn reads_section_headers_followed_by_a_comment() {
+        let mut file = WslConfigFile::parse(
+            "[wsl2] # settings for the VM\nnetworkingMode=mirrored\n[experimental] ; newer\n",
+        );
+        assert_eq!(file.get("wsl2", "networkingMode"), Some("mirrored"));
+        assert!(file.set("experimental", "hostAddressLoopback", "true"));
+        assert_eq!(
+            file.to_string(),
+            "[wsl2] # settings for the VM\nnetworkingMode=mirrored\n[experimental] ; newer\n\
+             hostAddressLoopback=true\n"
+        );
+        // Anything else after the header isn't a section
+        let file = WslConfigFile::parse("[wsl2] networkingMode=mirrored\n");
+        assert_eq!(file.get("wsl2", "networkingMode"), None);
+    }
+
+    #[test]
+    fn reads_the_settings() {
+        let file = WslConfigFile::parse(
+            "[wsl2]\nnetworkingMode=Mirrored\ndnsTunneling=TRUE\ndnsProxy=maybe\nvmIdleTimeout=60000\n",
+        );
+        assert_eq!(
+            WslConfig::from_file(&file),
+            WslConfig {
+                networking_mode: NetworkingMode::Mirrored,
+                dns_tunneling: Some(true),
+                dns_proxy: None,
+                vm_idle_timeout: Some(60000),
+                warnings: vec!["Invalid dnsProxy in .wslconfig: maybe".to_string()],
+            }
+        );
+    }
+}